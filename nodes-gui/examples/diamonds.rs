@@ -0,0 +1,120 @@
+// Times evaluation of graphs made of stacked diamonds, where every level adds the level below
+// to itself through both inputs of a Math node:
+//
+//     cargo run --release --example diamonds
+//
+// Without sharing, the bottom node of a graph `depth` levels deep runs 2^depth times per pixel,
+// as the unmemoized column shows for the shallow graphs. With it, both the tree-walker and
+// compiled programs grow linearly with depth.
+
+use std::{sync::Arc, time::Instant};
+
+use nodes_gui::app::{
+    basic_nodes::{attribute, basic_registry, math, node_tools::get_state_string_mut},
+    dependencies::Dependencies,
+    editor_graph::{EvalContext, NodeWorld},
+    interpreter,
+    storage::ID,
+    value::Value,
+};
+
+const SIDE: usize = 64;
+
+// Deeper graphs take too long to evaluate without the memo.
+const MAX_UNMEMOIZED_DEPTH: usize = 8;
+
+// The graph and its topmost output port, which computes x * 2^depth.
+fn diamonds(depth: usize) -> (NodeWorld, ID) {
+    let mut world = NodeWorld::new(Arc::new(basic_registry()));
+    let x = world.create_node(Default::default(), attribute::ATTRIBUTE);
+    *get_state_string_mut("name", &mut world.nodes.get_mut(x).state.state).unwrap() =
+        "x".to_string();
+    let mut below = world.nodes.get(x).ports[0];
+
+    for _ in 0..depth {
+        let level = world.create_node(Default::default(), math::MATH);
+        let ports = world.nodes.get(level).ports.clone();
+        let (a, b, out) = (ports[0], ports[1], ports[2]);
+        world.connect(below, a).unwrap();
+        world.connect(below, b).unwrap();
+        below = out;
+    }
+    (world, below)
+}
+
+fn pixel_context(x: f32, y: f32) -> EvalContext {
+    let mut ctx = EvalContext::new();
+    ctx.insert_mut("x".to_string(), Value::Float(x));
+    ctx.insert_mut("y".to_string(), Value::Float(y));
+    ctx
+}
+
+fn pixels() -> impl Iterator<Item = (f32, f32)> {
+    (0..SIDE * SIDE).map(|i| {
+        (
+            (i % SIDE) as f32 / SIDE as f32,
+            (i / SIDE) as f32 / SIDE as f32,
+        )
+    })
+}
+
+fn main() {
+    println!("{SIDE}x{SIDE} pixels");
+    println!(
+        "{:>6} {:>14} {:>14} {:>14}",
+        "depth", "unmemoized", "tree-walker", "compiled"
+    );
+    for depth in [1, 4, 8, 16, 32, 64] {
+        let (world, output) = diamonds(depth);
+
+        let unmemoized = if depth <= MAX_UNMEMOIZED_DEPTH {
+            let start = Instant::now();
+            let mut sum = 0f32;
+            for (x, y) in pixels() {
+                sum += world
+                    .evaluate_output_port_unmemoized(output, pixel_context(x, y))
+                    .unwrap()
+                    .as_f32()
+                    .unwrap();
+            }
+            let elapsed = start.elapsed();
+            assert_eq!(sum, tree_sum(&world, output));
+            format!("{elapsed:.2?}")
+        } else {
+            "-".to_string()
+        };
+
+        let start = Instant::now();
+        let tree_sum = tree_sum(&world, output);
+        let tree = start.elapsed();
+
+        let start = Instant::now();
+        let program = interpreter::compile(&world, output).unwrap();
+        let mut registers = program.new_registers();
+        let mut compiled_sum = 0f32;
+        for (x, _) in pixels() {
+            compiled_sum += program
+                .run(&mut registers, &[Value::Float(x)])
+                .and_then(|v| v.as_f32())
+                .unwrap();
+        }
+        let compiled = start.elapsed();
+
+        assert_eq!(tree_sum, compiled_sum);
+        println!("{depth:>6} {unmemoized:>14} {tree:>14.2?} {compiled:>14.2?}");
+    }
+}
+
+// Analyzes the graph once for all pixels, as a caller evaluating many contexts would.
+fn tree_sum(world: &NodeWorld, output: ID) -> f32 {
+    let deps = Arc::new(Dependencies::analyze(world));
+    pixels()
+        .map(|(x, y)| {
+            world
+                .evaluate_output_port_with(output, pixel_context(x, y), &deps)
+                .unwrap()
+                .as_f32()
+                .unwrap()
+        })
+        .sum()
+}
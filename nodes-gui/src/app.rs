@@ -1,5 +1,11 @@
 // https://github.com/emilk/eframe_template/blob/main/src/app.rs
 
+use std::{
+    collections::HashMap,
+    sync::{Arc, mpsc},
+    thread,
+};
+
 use egui::{
     Align, Color32, ColorImage, FontId, Painter, Pos2, Rect, Response, Sense, Shape, Stroke,
     TextureOptions, UiBuilder,
@@ -9,19 +15,40 @@ use egui::{
     vec2,
 };
 
+pub mod dependencies;
 pub mod editor_graph;
+pub mod export;
+pub mod expr;
+pub mod group;
+pub mod history;
+pub mod interpreter;
+pub mod registry;
+pub mod render;
+pub mod render_worker;
+pub mod serialization;
+pub mod signature;
+pub mod simplify;
 pub mod storage;
+pub mod value;
 use editor_graph::{Node, PortKind};
 
-use rpds::HashTrieMap;
 use storage::*;
 
 use crate::app::{
     basic_nodes::{
-        add::add_node_prototype, attribute::attribute_prototype, constant::constant_node_prototype,
-        exp::exp_prototype, image::done_node,
+        GROUP_CATEGORY, basic_registry,
+        group_io::{self, GROUP_INPUT},
+        image::{self, output_resolution},
     },
-    editor_graph::{NodePrototype, NodeWorld},
+    dependencies::Dependencies,
+    editor_graph::{EvalContext, EvalError, NodeWorld},
+    export::{BitDepth, ColorMode},
+    history::{History, MergeKey},
+    registry::PrototypeRegistry,
+    render::{MAX_RESOLUTION, RenderError, RenderSettings, Resolution, pixel_context},
+    render_worker::{RenderFailure, RenderWorker},
+    signature::Signatures,
+    value::{Value, ValueType},
 };
 
 pub mod basic_nodes;
@@ -32,40 +59,90 @@ pub struct App {
 
     value: f32,
 
+    file_path: String,
+    file_status: Option<String>,
+    export: ExportDialog,
+    // Status message of the export running in the background, sent once it finishes.
+    pending_export: Option<mpsc::Receiver<String>>,
+
     state: UIState,
 }
 
+// Settings of the File -> Export Image window, kept between exports.
+struct ExportDialog {
+    open: bool,
+    path: String,
+    width: usize,
+    height: usize,
+    depth: BitDepth,
+    mode: ColorMode,
+}
+
+impl Default for ExportDialog {
+    fn default() -> Self {
+        ExportDialog {
+            open: false,
+            path: "image.png".to_owned(),
+            width: 512,
+            height: 512,
+            depth: BitDepth::Eight,
+            mode: ColorMode::Rgba,
+        }
+    }
+}
+
 impl App {
     /// Called once before the first frame.
     pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
         // This is also where you can customize the look and feel of egui using
         // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
-        Self {
+        let mut app = Self {
             // Example stuff:
             label: "Hello World!".to_owned(),
             value: 2.7,
+            file_path: "graph.ron".to_owned(),
+            file_status: None,
+            export: Default::default(),
+            pending_export: None,
             state: UIState {
+                groups: Vec::new(),
+                asset_name: String::new(),
+                status: None,
                 world: Default::default(),
                 add_pos: None,
                 val: None,
                 interacting_mode: InteractingMode::Idle,
                 view_rect: Rect::from_min_size(Pos2 { x: 0f32, y: 0f32 }, vec2(200f32, 200f32)),
                 selection: Default::default(),
+                history: Default::default(),
                 texture_to_see: _cc.egui_ctx.load_texture(
                     "my_tex_name",
                     ColorImage::filled([128, 128], Color32::BLACK),
                     TextureOptions::default(),
                 ),
                 texture_outdated: true,
-                prototypes: vec![
-                    constant_node_prototype(),
-                    add_node_prototype(),
-                    done_node(),
-                    exp_prototype(),
-                    attribute_prototype(),
-                ],
+                rendered: HashMap::new(),
+                dependencies: Dependencies::default(),
+                errors: Vec::new(),
+                renderer: RenderWorker::new(_cc.egui_ctx.clone()),
+                prototypes: Arc::new(basic_registry()),
             },
+        };
+
+        let (assets, errors) = group::load_assets(&app.state.prototypes);
+        let registry = Arc::make_mut(&mut app.state.prototypes);
+        for asset in assets {
+            registry.register(group::ASSET_CATEGORY, asset);
+        }
+        app.state.world.set_registry(app.state.prototypes.clone());
+        if !errors.is_empty() {
+            let failed: Vec<String> = errors
+                .iter()
+                .map(|(name, e)| format!("{name}: {e}"))
+                .collect();
+            app.file_status = Some(format!("Could not load assets: {}", failed.join("; ")));
         }
+        app
     }
 }
 
@@ -75,6 +152,15 @@ impl eframe::App for App {
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
 
+        if !ctx.wants_keyboard_input() {
+            // Checked first since the plain shortcut also matches with shift held.
+            if ctx.input_mut(|i| i.consume_shortcut(&REDO_SHORTCUT)) {
+                self.state.redo();
+            } else if ctx.input_mut(|i| i.consume_shortcut(&UNDO_SHORTCUT)) {
+                self.state.undo();
+            }
+        }
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
 
@@ -83,6 +169,50 @@ impl eframe::App for App {
                 let is_web = cfg!(target_arch = "wasm32");
                 if !is_web {
                     ui.menu_button("File", |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("Path: ");
+                            ui.text_edit_singleline(&mut self.file_path);
+                        });
+                        if ui.button("Save").clicked() {
+                            let path = std::path::Path::new(&self.file_path);
+                            self.file_status = Some(
+                                match serialization::save_world_to_file(
+                                    &self.state.root_world(),
+                                    path,
+                                ) {
+                                    Ok(()) => format!("Saved to {}", self.file_path),
+                                    Err(e) => format!("Save failed: {e}"),
+                                },
+                            );
+                        }
+                        if ui.button("Open").clicked() {
+                            let path = std::path::Path::new(&self.file_path);
+                            self.file_status = Some(
+                                match serialization::load_world_from_file(
+                                    path,
+                                    &self.state.prototypes,
+                                ) {
+                                    Ok(world) => {
+                                        self.state.exit_all_groups();
+                                        self.state.history.record(&self.state.world);
+                                        self.state.world = world;
+                                        // IDs of the old graph would match nodes of the new one.
+                                        self.state.after_history_step();
+                                        format!("Opened {}", self.file_path)
+                                    }
+                                    Err(e) => format!("Open failed: {e}"),
+                                },
+                            );
+                        }
+                        if ui.button("Export Image...").clicked() {
+                            if let Some((world, out, _)) = self.state.export_target() {
+                                let resolution =
+                                    output_resolution(&world.nodes.get(out).state.state);
+                                self.export.width = resolution.width;
+                                self.export.height = resolution.height;
+                            }
+                            self.export.open = true;
+                        }
                         if ui.button("Quit").clicked() {
                             ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                         }
@@ -90,14 +220,61 @@ impl eframe::App for App {
                     ui.add_space(16.0);
                 }
 
+                ui.menu_button("Edit", |ui| {
+                    let undo = egui::Button::new("Undo")
+                        .shortcut_text(ui.ctx().format_shortcut(&UNDO_SHORTCUT));
+                    if ui
+                        .add_enabled(self.state.history.can_undo(), undo)
+                        .clicked()
+                    {
+                        self.state.undo();
+                    }
+                    let redo = egui::Button::new("Redo")
+                        .shortcut_text(ui.ctx().format_shortcut(&REDO_SHORTCUT));
+                    if ui
+                        .add_enabled(self.state.history.can_redo(), redo)
+                        .clicked()
+                    {
+                        self.state.redo();
+                    }
+
+                    ui.separator();
+                    let has_selection = !self.state.selection.selected_nodes.is_empty();
+                    if ui
+                        .add_enabled(has_selection, egui::Button::new("Group selected"))
+                        .clicked()
+                    {
+                        self.state.group_selected();
+                    }
+                    if ui.button("Simplify graph").clicked() {
+                        self.state.simplify_graph();
+                    }
+                    if ui
+                        .add_enabled(
+                            !self.state.groups.is_empty(),
+                            egui::Button::new("Exit group"),
+                        )
+                        .clicked()
+                    {
+                        self.state.exit_group();
+                    }
+                });
+                ui.add_space(16.0);
+
                 egui::widgets::global_theme_preference_buttons(ui);
             });
         });
 
+        self.export_window(ctx);
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // The central panel the region left after adding TopPanel's and SidePanel's
             ui.heading("eframe template");
 
+            if let Some(status) = &self.file_status {
+                ui.label(status);
+            }
+
             ui.horizontal(|ui| {
                 ui.label("Write something: ");
                 ui.text_edit_singleline(&mut self.label);
@@ -125,6 +302,84 @@ impl eframe::App for App {
     }
 }
 
+impl App {
+    fn export_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.export.open;
+        let mut export = false;
+        let dialog = &mut self.export;
+
+        egui::Window::new("Export Image")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Path: ");
+                    ui.text_edit_singleline(&mut dialog.path);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Size: ");
+                    ui.add(egui::DragValue::new(&mut dialog.width).range(1..=MAX_RESOLUTION));
+                    ui.label("x");
+                    ui.add(egui::DragValue::new(&mut dialog.height).range(1..=MAX_RESOLUTION));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Depth: ");
+                    ui.radio_value(&mut dialog.depth, BitDepth::Eight, "8-bit");
+                    ui.radio_value(&mut dialog.depth, BitDepth::Sixteen, "16-bit");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Channels: ");
+                    ui.radio_value(&mut dialog.mode, ColorMode::Gray, "Gray");
+                    ui.radio_value(&mut dialog.mode, ColorMode::Rgba, "RGBA");
+                });
+                export = ui.button("Export").clicked();
+            });
+
+        self.export.open = open;
+        if export && self.pending_export.is_none() {
+            self.file_status = Some(match self.export_image(ctx) {
+                Ok(()) => format!("Exporting {}...", self.export.path),
+                Err(e) => format!("Export failed: {e}"),
+            });
+        }
+
+        if let Some(status) = self.pending_export.as_ref().and_then(|r| r.try_recv().ok()) {
+            self.pending_export = None;
+            self.file_status = Some(status);
+        }
+    }
+
+    // Renders on a background thread like `RenderWorker`, so large exports don't freeze the
+    // editor. `ctx` is asked to repaint once the file is written.
+    fn export_image(&mut self, ctx: &egui::Context) -> Result<(), String> {
+        let (world, _, output) = self
+            .state
+            .export_target()
+            .ok_or("no connected Out node to export")?;
+        let dialog = &self.export;
+        let settings = RenderSettings::new(Resolution::new(dialog.width, dialog.height));
+        let path = std::path::PathBuf::from(&dialog.path);
+        let (depth, mode) = (dialog.depth, dialog.mode);
+
+        let (sender, receiver) = mpsc::channel();
+        let ctx = ctx.clone();
+        thread::Builder::new()
+            .name("export".to_string())
+            .spawn(move || {
+                let status =
+                    match export::render_to_file(&world, output, &settings, &path, depth, mode) {
+                        Ok(()) => format!("Exported {}", path.display()),
+                        Err(e) => format!("Export failed: {e}"),
+                    };
+                let _ = sender.send(status);
+                ctx.request_repaint();
+            })
+            .map_err(|e| e.to_string())?;
+        self.pending_export = Some(receiver);
+        Ok(())
+    }
+}
+
 fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
     ui.horizontal(|ui| {
         ui.spacing_mut().item_spacing.x = 0.0;
@@ -139,6 +394,13 @@ fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
     });
 }
 
+const UNDO_SHORTCUT: egui::KeyboardShortcut =
+    egui::KeyboardShortcut::new(egui::Modifiers::COMMAND, egui::Key::Z);
+const REDO_SHORTCUT: egui::KeyboardShortcut = egui::KeyboardShortcut::new(
+    egui::Modifiers::COMMAND.plus(egui::Modifiers::SHIFT),
+    egui::Key::Z,
+);
+
 enum DrawingConnection {
     FromInput(ID),
     FromOutput(ID),
@@ -153,31 +415,222 @@ enum InteractingMode {
 struct UIState {
     world: NodeWorld,
     add_pos: Option<Pos2>,
-    val: Option<f32>,
+    val: Option<Value>,
     view_rect: Rect,
     interacting_mode: InteractingMode,
     selection: SelectionState,
-    prototypes: Vec<NodePrototype>,
+    // Shared with every graph being edited, see `sync_registry`.
+    prototypes: Arc<PrototypeRegistry>,
+    history: History,
 
+    // Set by any edit; the preview is only re-rendered if the edit changed what an Out node shows.
     texture_outdated: bool,
+    // Signature of the port each Out node was last rendered from, and at which size.
+    rendered: HashMap<ID, (u64, Resolution)>,
+    // What the ports of the graph being edited read, updated along with the preview.
+    dependencies: Dependencies,
+    // Why ports of the graph being edited that feed an output fail to evaluate, one error per
+    // port it starts at.
+    errors: Vec<EvalError>,
     texture_to_see: egui::TextureHandle,
+    renderer: RenderWorker,
+
+    // Outermost first, the graphs around the group currently being edited in `world`.
+    groups: Vec<GroupFrame>,
+    asset_name: String,
+    status: Option<String>,
+}
+
+struct GroupFrame {
+    world: NodeWorld,
+    // The group node in `world` whose subgraph is being edited.
+    node: ID,
+    history: History,
+    view_rect: Rect,
 }
 
 #[derive(Default)]
 struct SelectionState {
-    _selected_nodes: Vec<ID>,
+    selected_nodes: Vec<ID>,
     hovered_port: Option<ID>,
 }
 
+impl SelectionState {
+    fn select(&mut self, node: ID, toggle: bool) {
+        if !toggle {
+            self.selected_nodes.clear();
+        }
+        if let Some(i) = self.selected_nodes.iter().position(|n| *n == node) {
+            self.selected_nodes.remove(i);
+        } else {
+            self.selected_nodes.push(node);
+        }
+    }
+}
+
 struct DrawingState {
     lines: Vec<Shape>,
     other_shapes: Vec<Shape>,
 }
 
 impl UIState {
+    // The whole graph, with edits to any group being edited applied, along with its selected
+    // Out node if there is one, else the first, and the output port feeding it.
+    fn export_target(&self) -> Option<(NodeWorld, ID, ID)> {
+        let world = self.root_world();
+        let connected = |id: &ID| {
+            let node = world.nodes.try_get(*id)?;
+            if node.key != image::OUT {
+                return None;
+            }
+            match world.ports.get(node.ports[0]).connection_kind {
+                PortKind::Input(Some(output)) => Some((*id, output)),
+                _ => None,
+            }
+        };
+        // Selections inside a group refer to another graph.
+        let selected = if self.groups.is_empty() {
+            &self.selection.selected_nodes[..]
+        } else {
+            &[]
+        };
+        let (out, output) = selected
+            .iter()
+            .find_map(connected)
+            .or_else(|| world.nodes.ids().find_map(connected))?;
+        Some((world, out, output))
+    }
+
+    // The top level graph as it would be after leaving every group being edited.
+    fn root_world(&self) -> NodeWorld {
+        let mut world = self.world.clone();
+        for frame in self.groups.iter().rev() {
+            let mut outer = frame.world.clone();
+            group::apply_edit(&mut outer, frame.node, world);
+            world = outer;
+        }
+        world
+    }
+
+    fn enter_group(&mut self, node: ID) {
+        let Some(inner) = self.world.subgraph(self.world.nodes.get(node)).cloned() else {
+            return;
+        };
+        let outer = std::mem::replace(&mut self.world, (*inner).clone());
+        self.groups.push(GroupFrame {
+            world: outer,
+            node,
+            history: std::mem::take(&mut self.history),
+            view_rect: self.view_rect,
+        });
+        self.after_history_step();
+    }
+
+    // Leaving a group applies the edits made inside it to the group node as one undo step.
+    fn exit_group(&mut self) -> bool {
+        let Some(frame) = self.groups.pop() else {
+            return false;
+        };
+        let inner = std::mem::replace(&mut self.world, frame.world);
+        let edited = self.history.can_undo();
+        self.history = frame.history;
+        self.view_rect = frame.view_rect;
+        if edited {
+            self.history.record(&self.world);
+            group::apply_edit(&mut self.world, frame.node, inner);
+        }
+        self.after_history_step();
+        true
+    }
+
+    fn exit_all_groups(&mut self) {
+        while self.exit_group() {}
+    }
+
+    fn group_selected(&mut self) {
+        let before = self.world.clone();
+        match group::group_nodes(&mut self.world, &self.selection.selected_nodes) {
+            Ok(group) => {
+                self.history.record(&before);
+                self.selection.selected_nodes = vec![group];
+                self.texture_outdated = true;
+                self.status = None;
+            }
+            Err(e) => self.status = Some(format!("Can't group: {e}")),
+        }
+    }
+
+    // Leaves the graph and its history alone when there is nothing to simplify.
+    fn simplify_graph(&mut self) {
+        let mut simplified = self.world.clone();
+        let report = simplify::simplify(&mut simplified);
+        if !report.is_empty() {
+            self.history.record(&self.world);
+            self.world = simplified;
+            self.after_history_step();
+        }
+        self.status = Some(format!("Simplified: {report}"));
+    }
+
+    fn save_asset(&mut self, node: ID) {
+        let name = self.asset_name.trim().to_string();
+        let Some(inner) = self.world.subgraph(self.world.nodes.get(node)).cloned() else {
+            return;
+        };
+        if name.is_empty() {
+            self.status = Some("Asset needs a name".to_string());
+            return;
+        }
+        self.status = Some(match group::save_asset(&name, &inner) {
+            Ok(()) => {
+                let key = group::asset_key(&name);
+                Arc::make_mut(&mut self.prototypes).register(
+                    group::ASSET_CATEGORY,
+                    group::group_prototype(&key, (*inner).clone()),
+                );
+                self.history.record(&self.world);
+                group::bind_asset(&mut self.world, node, &key);
+                // Other instances of an asset saved again pick up the new version.
+                self.sync_registry();
+                self.texture_outdated = true;
+                format!("Saved asset {name}")
+            }
+            Err(e) => format!("Saving asset failed: {e}"),
+        });
+    }
+
+    fn undo(&mut self) {
+        if self.history.undo(&mut self.world) {
+            self.after_history_step();
+        }
+    }
+
+    fn redo(&mut self) {
+        if self.history.redo(&mut self.world) {
+            self.after_history_step();
+        }
+    }
+
+    // Points every graph being edited at the current prototypes. Restored or entered graphs
+    // can still refer to older ones.
+    fn sync_registry(&mut self) {
+        self.world.set_registry(self.prototypes.clone());
+        for frame in &mut self.groups {
+            frame.world.set_registry(self.prototypes.clone());
+        }
+    }
+
+    // IDs held by the editor may not exist in the restored world.
+    fn after_history_step(&mut self) {
+        self.sync_registry();
+        self.selection = SelectionState::default();
+        self.interacting_mode = InteractingMode::Idle;
+        self.texture_outdated = true;
+    }
+
     fn selected_node(&self, pos: Pos2) -> Option<(ID, &Node)> {
         for (id, n) in self.world.nodes.with_ids() {
-            let rect = Rect::from_min_size(n.pos, n.prototype.size);
+            let rect = Rect::from_min_size(n.pos, self.world.node_size(n));
             if rect.contains(pos) {
                 return Some((*id, n));
             }
@@ -193,17 +646,17 @@ impl UIState {
     ) {
         self.selection.hovered_port = None;
 
-        let inputs_hoverable = match &self.interacting_mode {
-            InteractingMode::Idle => true,
-            InteractingMode::DrawingConnection(DrawingConnection::FromOutput(_)) => true,
-            _ => false,
-        };
+        let inputs_hoverable = matches!(
+            &self.interacting_mode,
+            InteractingMode::Idle
+                | InteractingMode::DrawingConnection(DrawingConnection::FromOutput(_))
+        );
 
-        let outputs_hoverable = match &self.interacting_mode {
-            InteractingMode::Idle => true,
-            InteractingMode::DrawingConnection(DrawingConnection::FromInput(_)) => true,
-            _ => false,
-        };
+        let outputs_hoverable = matches!(
+            &self.interacting_mode,
+            InteractingMode::Idle
+                | InteractingMode::DrawingConnection(DrawingConnection::FromInput(_))
+        );
 
         let mut mouse_pos = response.hover_pos().or(response.interact_pointer_pos());
         let contains_ptr = ui.ui_contains_pointer();
@@ -213,30 +666,78 @@ impl UIState {
         let _drag_stopped = response.drag_stopped();
 
         let _hovered_node = if contains_ptr {
-            mouse_pos.map(|f| self.selected_node(f)).flatten()
+            mouse_pos.and_then(|f| self.selected_node(f))
         } else {
             None
         };
 
-        let nodes_draggable = match &self.interacting_mode {
-            InteractingMode::Idle => true,
-            _ => false,
-        };
+        let nodes_draggable = matches!(&self.interacting_mode, InteractingMode::Idle);
 
-        let node_ids: Vec<ID> = self.world.nodes.ids().clone();
+        let node_ids: Vec<ID> = self.world.nodes.ids().copied().collect();
 
         let mut create_line_if_able = false;
+        let mut nodes_to_delete = Vec::new();
+        let mut inputs_to_disconnect = Vec::new();
+        let mut picked_up_wire = false;
+        let mut group_to_enter = None;
+        let mut group_selection = false;
+        let mut asset_to_save = None;
 
         self.selection.hovered_port = None;
 
+        if response.clicked() {
+            self.selection.selected_nodes.clear();
+        }
+
+        // A new press always starts a new undo step.
+        if ui.input(|i| i.pointer.any_pressed()) {
+            self.history.close_edit();
+        }
+
         for i in node_ids {
             let n = self.world.nodes.get(i);
+            let is_group = self.world.subgraph(n).is_some();
             let node_rect = ui.allocate_rect(
-                Rect::from_min_size(n.pos, n.prototype.size),
+                Rect::from_min_size(n.pos, self.world.node_size(n)),
                 Sense::click_and_drag(),
             );
 
-            let n = self.world.nodes.get_mut(i);
+            if node_rect.clicked() {
+                self.selection.select(i, ui.input(|i| i.modifiers.shift));
+            }
+            if is_group && node_rect.double_clicked() {
+                group_to_enter = Some(i);
+            }
+
+            node_rect.context_menu(|ui| {
+                if ui.button("Delete").clicked() {
+                    nodes_to_delete.push(i);
+                }
+                if ui.button("Group selected").clicked() {
+                    if !self.selection.selected_nodes.contains(&i) {
+                        self.selection.select(i, false);
+                    }
+                    group_selection = true;
+                }
+                if is_group {
+                    if ui.button("Enter group").clicked() {
+                        group_to_enter = Some(i);
+                    }
+                    ui.horizontal(|ui| {
+                        ui.text_edit_singleline(&mut self.asset_name);
+                        if ui.button("Save as asset").clicked() {
+                            asset_to_save = Some(i);
+                        }
+                    });
+                }
+            });
+
+            if nodes_draggable && node_rect.drag_started() {
+                if !self.selection.selected_nodes.contains(&i) {
+                    self.selection.select(i, false);
+                }
+                self.history.record(&self.world);
+            }
 
             if nodes_draggable {
                 if node_rect.drag_started() {
@@ -244,47 +745,91 @@ impl UIState {
                     //    InteractingMode::Moving(node_rect.interact_pointer_pos().unwrap(), *i);
                 } else if node_rect.dragged() {
                     let delta = node_rect.drag_delta();
-                    n.pos += delta;
+                    self.world.nodes.get_mut(i).pos += delta;
                 } else if node_rect.drag_stopped() {
                     self.interacting_mode = InteractingMode::Idle;
                 }
             }
 
-            let n = &*n;
-
-            for p in &n.ports {
+            for p in &self.world.nodes.get(i).ports {
                 let port = self.world.ports.get(*p);
                 let port_rect = ui.allocate_rect(
-                    Rect::from_center_size(
-                        n.pos + port.port_info.local_position,
-                        vec2(20f32, 20f32),
-                    ),
-                    Sense::drag(),
+                    Rect::from_center_size(self.world.get_port_pos(*p), vec2(20f32, 20f32)),
+                    Sense::click_and_drag(),
                 );
 
                 if port_rect.drag_started() {
                     self.interacting_mode =
                         InteractingMode::DrawingConnection(match port.connection_kind {
-                            PortKind::Input(_) => DrawingConnection::FromInput(*p),
-                            PortKind::Output(_) => DrawingConnection::FromOutput(*p),
+                            // Dragging an existing wire off an input picks it up from its output.
+                            PortKind::Input(Some(out)) => {
+                                picked_up_wire = true;
+                                inputs_to_disconnect.push(*p);
+                                DrawingConnection::FromOutput(out)
+                            }
+                            PortKind::Input(None) => DrawingConnection::FromInput(*p),
+                            PortKind::Output => DrawingConnection::FromOutput(*p),
                         });
                 }
+                if port_rect.secondary_clicked() && port.connection_kind.is_input() {
+                    inputs_to_disconnect.push(*p);
+                }
                 if port_rect.drag_stopped() {
                     create_line_if_able = true;
                 }
 
-                if port_rect.contains_pointer() {
-                    if (inputs_hoverable && port.connection_kind.is_input())
-                        || (outputs_hoverable && port.connection_kind.is_output())
-                    {
-                        self.selection.hovered_port = Some(*p);
-                    }
+                if port_rect.contains_pointer()
+                    && ((inputs_hoverable && port.connection_kind.is_input())
+                        || (outputs_hoverable && port.connection_kind.is_output()))
+                {
+                    self.selection.hovered_port = Some(*p);
                 }
 
                 mouse_pos = mouse_pos.or(port_rect.interact_pointer_pos());
             }
         }
 
+        if nodes_draggable
+            && !ui.ctx().wants_keyboard_input()
+            && ui.input(|i| i.key_pressed(egui::Key::Delete))
+        {
+            nodes_to_delete.extend(self.selection.selected_nodes.iter().copied());
+        }
+
+        // Picking up a wire and dropping it elsewhere undoes as one step.
+        if picked_up_wire {
+            self.history.record_merged(MergeKey::Link, &self.world);
+        } else if !inputs_to_disconnect.is_empty() {
+            self.history.record(&self.world);
+        }
+        for inp in inputs_to_disconnect {
+            self.world.disconnect(inp);
+            self.texture_outdated = true;
+        }
+
+        if !nodes_to_delete.is_empty() {
+            self.history.record(&self.world);
+        }
+        for n in nodes_to_delete {
+            if self.world.nodes.exists(n) {
+                self.world.remove_node(n);
+                self.selection.selected_nodes.retain(|s| *s != n);
+                self.texture_outdated = true;
+            }
+        }
+
+        if group_selection {
+            self.group_selected();
+        }
+        if let Some(node) = asset_to_save {
+            self.save_asset(node);
+        }
+        // Entered last, every ID above refers to the graph around the group.
+        if let Some(node) = group_to_enter {
+            self.enter_group(node);
+            return;
+        }
+
         match &self.interacting_mode {
             InteractingMode::Idle => {}
             InteractingMode::DrawingConnection(con) => {
@@ -293,13 +838,22 @@ impl UIState {
                         DrawingConnection::FromInput(i) => *i,
                         DrawingConnection::FromOutput(i) => *i,
                     };
-                    if let Some(snap_port) = self.selection.hovered_port {
-                        if self.world.ports.get(snap_port).node
-                            == self.world.ports.get(begin_port).node
-                        {
-                            self.selection.hovered_port = None;
+                    let link_ends = |hovered| match con {
+                        DrawingConnection::FromInput(_) => (hovered, Some(begin_port)),
+                        DrawingConnection::FromOutput(_) => (Some(begin_port), hovered),
+                    };
+
+                    // Only snap to ports the wire could actually be connected to.
+                    let mut rejection = None;
+                    if let (Some(out), Some(inp)) = link_ends(self.selection.hovered_port)
+                        && let Err(e) = self.world.can_connect(out, inp)
+                    {
+                        if self.selection.hovered_port != Some(begin_port) {
+                            rejection = Some(e);
                         }
+                        self.selection.hovered_port = None;
                     }
+                    let (outp_port, inp_port) = link_ends(self.selection.hovered_port);
 
                     let mut start_point = if let Some(end_port) = self.selection.hovered_port {
                         self.world.get_port_pos(end_port)
@@ -313,20 +867,38 @@ impl UIState {
                         std::mem::swap(&mut dest_point, &mut start_point);
                     }
 
-                    draw_line(&mut drawing_state.lines, start_point, dest_point, 100usize);
+                    let wire_color = match (rejection, outp_port) {
+                        (Some(_), _) => Color32::RED,
+                        (None, Some(out)) => type_color(self.world.port_info(out).ty),
+                        (None, None) => Color32::WHITE,
+                    };
+                    draw_line(
+                        &mut drawing_state.lines,
+                        start_point,
+                        dest_point,
+                        100usize,
+                        wire_color,
+                    );
 
-                    if create_line_if_able {
-                        let (outp_port, inp_port) = match con {
-                            DrawingConnection::FromInput(_) => {
-                                (self.selection.hovered_port, Some(begin_port))
-                            }
-                            DrawingConnection::FromOutput(_) => {
-                                (Some(begin_port), self.selection.hovered_port)
-                            }
-                        };
+                    if let Some(e) = rejection {
+                        drawing_state.other_shapes.push(
+                            draw_text(
+                                ui.painter(),
+                                e.to_string(),
+                                pos + vec2(10f32, -10f32),
+                                12f32,
+                                Align::LEFT,
+                                Align::BOTTOM,
+                            )
+                            .into(),
+                        );
+                    }
 
-                        if let (out, Some(inp)) = (outp_port, inp_port) {
-                            self.world.ports.get_mut(inp).connection_kind = PortKind::Input(out);
+                    if create_line_if_able {
+                        if let (Some(out), Some(inp)) = (outp_port, inp_port) {
+                            self.history.record_merged(MergeKey::Link, &self.world);
+                            // Checked above, the hovered port is cleared for invalid links.
+                            let _ = self.world.connect(out, inp);
                         }
 
                         self.interacting_mode = InteractingMode::Idle;
@@ -352,11 +924,21 @@ fn smoother_step(t: f32) -> f32 {
     ((6f32 * t - 15f32) * t + 10f32) * t.powi(3)
 }
 
-fn draw_line(lines: &mut Vec<Shape>, start_pt: Pos2, end_pt: Pos2, steps: usize) {
+fn type_color(ty: ValueType) -> Color32 {
+    match ty {
+        ValueType::Float => Color32::from_rgb(160, 160, 160),
+        ValueType::Vec2 => Color32::from_rgb(100, 100, 220),
+        ValueType::Vec3 => Color32::from_rgb(100, 180, 240),
+        ValueType::Color => Color32::from_rgb(230, 200, 40),
+        ValueType::Bool => Color32::from_rgb(220, 120, 180),
+        ValueType::Int => Color32::from_rgb(80, 190, 120),
+    }
+}
+
+fn draw_line(lines: &mut Vec<Shape>, start_pt: Pos2, end_pt: Pos2, steps: usize, color: Color32) {
     let dist = end_pt - start_pt;
     let steps = steps + 2;
     let pts: Vec<Pos2> = (0..=steps)
-        .into_iter()
         .map(|p| {
             let t = p as f32 / steps as f32;
             let smooth_t = smoother_step(t);
@@ -369,11 +951,10 @@ fn draw_line(lines: &mut Vec<Shape>, start_pt: Pos2, end_pt: Pos2, steps: usize)
             } else {
                 1.3 * dist.x
             };
-            let v = Pos2 {
+            Pos2 {
                 x: smooth_t * (dist.x - k) + k * t + start_pt.x,
                 y: smooth_t * dist.y + start_pt.y,
-            };
-            v
+            }
         })
         .collect();
 
@@ -383,7 +964,7 @@ fn draw_line(lines: &mut Vec<Shape>, start_pt: Pos2, end_pt: Pos2, steps: usize)
         fill: Color32::TRANSPARENT,
         stroke: PathStroke {
             width: 3f32,
-            color: egui::epaint::ColorMode::Solid(Color32::WHITE),
+            color: egui::epaint::ColorMode::Solid(color),
             kind: egui::StrokeKind::Middle,
         },
     };
@@ -445,17 +1026,25 @@ fn draw_single_node(
     painter: &Painter,
     shapes: &mut Vec<Shape>,
     world: &NodeWorld,
+    id: ID,
     node: &Node,
     select_state: &SelectionState,
+    dependencies: &Dependencies,
 ) {
+    let outline = if select_state.selected_nodes.contains(&id) {
+        Color32::ORANGE
+    } else {
+        Color32::WHITE
+    };
+
     let r: Shape = RectShape {
         rect: Rect {
             min: node.pos,
-            max: node.pos + node.prototype.size,
+            max: node.pos + world.node_size(node),
         },
         corner_radius: 10f32.into(),
         fill: Color32::BLACK,
-        stroke: Stroke::new(3f32, Color32::WHITE),
+        stroke: Stroke::new(3f32, outline),
         stroke_kind: egui::StrokeKind::Middle,
         round_to_pixels: None,
         blur_width: 0f32,
@@ -466,7 +1055,7 @@ fn draw_single_node(
 
     let name_label = draw_text(
         painter,
-        node.prototype.name.clone(),
+        world.prototype(node).name.clone(),
         node.pos + vec2(20f32, 20f32),
         14f32,
         Align::LEFT,
@@ -477,13 +1066,40 @@ fn draw_single_node(
     shapes.push(r);
     shapes.push(name_label);
 
+    // Whether the node's outputs are the same for every pixel, once they all compile.
+    let uniform: Option<Vec<bool>> = node
+        .ports
+        .iter()
+        .filter(|p| world.ports.get(**p).connection_kind.is_output())
+        .map(|p| dependencies.is_uniform(*p))
+        .collect();
+    if let Some(uniform) = uniform.filter(|u| !u.is_empty()) {
+        let label = if uniform.iter().all(|u| *u) {
+            "uniform"
+        } else {
+            "varying"
+        };
+        shapes.push(
+            draw_text(
+                painter,
+                label.to_string(),
+                node.pos + world.node_size(node) - vec2(15f32, 10f32),
+                10f32,
+                Align::RIGHT,
+                Align::BOTTOM,
+            )
+            .into(),
+        );
+    }
+
     for inp in &node.ports {
         let p = world.ports.get(*inp);
+        let info = world.port_info(*inp);
         draw_port(
             shapes,
             painter,
-            p.port_info.name.clone(),
-            node.pos + p.port_info.local_position,
+            info.name.clone(),
+            node.pos + info.local_position,
             if p.connection_kind.is_input() {
                 Align::LEFT
             } else {
@@ -492,35 +1108,67 @@ fn draw_single_node(
             if select_state.hovered_port == Some(*inp) {
                 Color32::WHITE
             } else {
-                Color32::RED
+                type_color(info.ty)
             },
         );
     }
 }
 
-fn get_bytes_array(world: &NodeWorld, id: ID) -> Option<[u8; 128 * 128]> {
-    const WIDTH: usize = 128;
-    const HEIGHT: usize = 128;
-    let mut bytes = [0u8; WIDTH * HEIGHT];
-
-    for x in 0..WIDTH {
-        for y in 0..HEIGHT {
-            let b = world.evaluate_output_port(
-                id,
-                HashTrieMap::new()
-                    .insert("x".to_string(), x as f32 / WIDTH as f32)
-                    .insert("y".to_string(), y as f32 / HEIGHT as f32),
-            )?;
-            let ind = y * WIDTH + x;
-            let byte = (b * 255f32) as u8;
-            bytes[ind] = byte;
-            //bytes[ind * 3] = byte;
-            //bytes[ind * 3 + 1] = byte;
-            //bytes[ind * 3 + 2] = byte;
-        }
-    }
-
-    Some(bytes)
+// Marks nodes listed in the error panel.
+fn draw_error_badge(painter: &Painter, shapes: &mut Vec<Shape>, world: &NodeWorld, node: &Node) {
+    let corner = node.pos + vec2(world.node_size(node).x, 0f32);
+    shapes.push(Shape::circle_filled(corner, 9f32, Color32::RED));
+    shapes.push(
+        draw_text(
+            painter,
+            "!".to_string(),
+            corner,
+            12f32,
+            Align::Center,
+            Align::Center,
+        )
+        .into(),
+    );
+}
+
+// The attributes of the pixel at the centre of an image.
+fn centre_context(resolution: Resolution) -> EvalContext {
+    pixel_context(resolution, resolution.width / 2, resolution.height / 2)
+}
+
+// Evaluates every output of the nodes feeding an output of `world` and collects why they fail.
+// Group inputs get placeholder values, so the graph of a group can be checked on its own.
+fn evaluation_errors(world: &NodeWorld, mut ctx: EvalContext) -> Vec<EvalError> {
+    for n in world.nodes.iter() {
+        if n.key == GROUP_INPUT
+            && let Some(name) = group_io::io_name(&n.state.state)
+        {
+            let ty = world.port_info(n.ports[0]).ty;
+            if let Some(val) = Value::Float(0f32).convert(ty) {
+                ctx.insert_mut(group::input_key(name), val);
+            }
+        }
+    }
+
+    let used = basic_nodes::used_nodes(world, &basic_nodes::output_nodes(world));
+    let mut errors: Vec<EvalError> = Vec::new();
+    for node in world.nodes.ids() {
+        if !used.contains(node) {
+            continue;
+        }
+        for p in &world.nodes.get(*node).ports {
+            if world.ports.get(*p).connection_kind.is_input() {
+                continue;
+            }
+            // Ports downstream of a failing one fail with the same error.
+            if let Err(e) = world.evaluate_output_port(*p, ctx.clone())
+                && !errors.iter().any(|o| o.node == e.node && o.port == e.port)
+            {
+                errors.push(e);
+            }
+        }
+    }
+    errors
 }
 
 fn draw_node(ui: &mut egui::Ui, ui_state: &mut UIState) {
@@ -548,40 +1196,124 @@ fn draw_node(ui: &mut egui::Ui, ui_state: &mut UIState) {
 
     ui.label(format!("Hello! Value: {:?}", ui_state.val));
 
+    if !ui_state.groups.is_empty() {
+        ui.horizontal(|ui| {
+            let path: Vec<String> = ui_state
+                .groups
+                .iter()
+                .map(|frame| {
+                    let world = &frame.world;
+                    world.prototype(world.nodes.get(frame.node)).name.clone()
+                })
+                .collect();
+            ui.label(format!("Editing group: {}", path.join(" > ")));
+            if ui.button("Exit group").clicked() {
+                ui_state.exit_group();
+            }
+        });
+    }
+    if let Some(status) = &ui_state.status {
+        ui.label(status);
+    }
+    if !ui_state.errors.is_empty() {
+        let mut clicked = None;
+        egui::CollapsingHeader::new(format!("Errors ({})", ui_state.errors.len()))
+            .default_open(true)
+            .show(ui, |ui| {
+                for e in &ui_state.errors {
+                    let node = e.node.filter(|n| ui_state.world.nodes.exists(*n));
+                    let world = &ui_state.world;
+                    let name =
+                        node.map_or("?", |n| world.prototype(world.nodes.get(n)).name.as_str());
+                    let text = egui::RichText::new(format!("{name}: {e}")).color(Color32::RED);
+                    if ui.selectable_label(false, text).clicked() {
+                        clicked = node;
+                    }
+                }
+            });
+        // Selects the node the error is at, to find it in the graph.
+        if let Some(node) = clicked {
+            ui_state.selection.selected_nodes = vec![node];
+        }
+    }
+
     if ui_state.texture_outdated {
-        for n in &ui_state.world.nodes {
-            if n.prototype.name == "Out" {
-                let inp = match ui_state.world.ports.get(n.ports[0]).connection_kind {
+        // Inside a group, the preview still shows the whole graph with the edits applied.
+        let root = ui_state.root_world();
+        let mut signatures = Signatures::new(&root);
+        let mut outputs = Vec::new();
+        let mut rendered = HashMap::new();
+        for (id, n) in root.nodes.with_ids() {
+            if n.key == image::OUT {
+                let inp = match root.ports.get(n.ports[0]).connection_kind {
                     PortKind::Input(i) => i,
                     _ => panic!(),
                 };
+                let resolution = output_resolution(&n.state.state);
+                if let Some(op) = inp {
+                    rendered.insert(*id, (signatures.port(op), resolution));
+                }
+                outputs.push((inp, resolution));
+            }
+        }
+
+        ui_state.dependencies = Dependencies::analyze(&ui_state.world);
+        // With nothing to render, no render failure will replace the errors.
+        if rendered.is_empty() {
+            ui_state.errors.clear();
+        }
 
+        // Edits that no Out node depends on, e.g. to unconnected nodes, don't re-render.
+        if rendered != ui_state.rendered {
+            for (inp, resolution) in outputs {
                 ui_state.val = match inp {
-                    Some(op) => ui_state.world.evaluate_output_port(op, HashTrieMap::new()),
+                    Some(op) => root
+                        .evaluate_output_port(op, centre_context(resolution))
+                        .ok(),
                     None => None,
                 };
 
                 if let Some(op) = inp {
-                    let bytes = get_bytes_array(&ui_state.world, op);
-                    if let Some(bytes) = bytes {
-                        ui_state.texture_to_see.set(
-                            ColorImage::from_gray([128, 128], &bytes),
-                            TextureOptions::NEAREST,
-                        );
-                    }
+                    ui_state.renderer.request(root.clone(), op, resolution);
                 }
             }
+            ui_state.rendered = rendered;
         }
 
         ui_state.texture_outdated = false;
     }
 
+    match ui_state.renderer.poll() {
+        Some(Ok(image)) => {
+            ui_state.texture_to_see.set(
+                ColorImage::from_rgba_unmultiplied(image.size, &image.rgba),
+                TextureOptions::NEAREST,
+            );
+            ui_state.errors.clear();
+        }
+        // Failures leave the last good preview on screen. The graph is checked at the pixel
+        // the render failed at; other failures happen at every pixel.
+        Some(Err(RenderFailure { resolution, error })) => {
+            let ctx = match error {
+                RenderError::Evaluation { px, py, .. } => pixel_context(resolution, px, py),
+                _ => centre_context(resolution),
+            };
+            ui_state.errors = evaluation_errors(&ui_state.world, ctx);
+        }
+        None => {}
+    }
+
     let mut vrect = ui_state.view_rect;
 
+    // Fit the preview inside the panel without stretching it.
+    let available = ui.available_rect_before_wrap();
+    let preview_size = ui_state.texture_to_see.size_vec2();
+    let scale = (available.width() / preview_size.x).min(available.height() / preview_size.y);
+
     ui.painter().image(
         ui_state.texture_to_see.id(),
         //Rect::from_min_size(Pos2::ZERO, vec2(128f32, 128f32)),
-        ui.available_rect_before_wrap(),
+        Rect::from_center_size(available.center(), preview_size * scale),
         Rect::from_min_max(Pos2::ZERO, pos2(1f32, 1f32)),
         Color32::WHITE,
     );
@@ -608,64 +1340,107 @@ fn draw_node(ui: &mut egui::Ui, ui_state: &mut UIState) {
                 }
             };
 
-            for p in &ui_state.prototypes {
-                if ui.button(&p.name).clicked() {
-                    ui_state.world.create_node(pos, &p);
+            let in_group = !ui_state.groups.is_empty();
+            let mut chosen = None;
+            for category in ui_state.prototypes.categories() {
+                // Group inputs and outputs mean nothing outside a group.
+                if !in_group && category == GROUP_CATEGORY {
+                    continue;
                 }
+                ui.menu_button(category, |ui| {
+                    for entry in ui_state.prototypes.entries() {
+                        if entry.category == category && ui.button(&entry.prototype.name).clicked()
+                        {
+                            chosen = Some(entry.prototype.key.clone());
+                        }
+                    }
+                });
+            }
+            if let Some(key) = chosen {
+                ui_state.history.record(&ui_state.world);
+                let id = ui_state.world.create_node(pos, &key);
+                basic_nodes::sync_node(&mut ui_state.world, id);
+                ui_state.texture_outdated = true;
             }
         });
 
         let painter = ui.painter();
 
-        for p in &ui_state.world.ports {
-            match &p.connection_kind {
-                PortKind::Input(Some(outp_id)) => {
-                    let l = (
-                        ui_state.world.get_port_pos(*outp_id),
-                        ui_state.world.get_port_pos_from_ref(p),
-                    );
+        for (id, p) in ui_state.world.ports.with_ids() {
+            if let PortKind::Input(Some(outp_id)) = &p.connection_kind
+                && ui_state.world.ports.exists(*outp_id)
+            {
+                let l = (
+                    ui_state.world.get_port_pos(*outp_id),
+                    ui_state.world.get_port_pos(*id),
+                );
 
-                    let diff = /*(*/ l.1 - l.0 /*)*/; // * ui_state.view.scaling;
-                    let len = (diff.length() / 10f32).max(1f32).min(100f32);
-                    draw_line(&mut draw.lines, l.0, l.1, len as usize);
-                }
-                _ => {}
+                let diff = /*(*/ l.1 - l.0 /*)*/; // * ui_state.view.scaling;
+                let len = (diff.length() / 10f32).clamp(1f32, 100f32);
+                let color = type_color(ui_state.world.port_info(*outp_id).ty);
+                draw_line(&mut draw.lines, l.0, l.1, len as usize, color);
             }
         }
 
-        for n in &ui_state.world.nodes {
+        for (id, n) in ui_state.world.nodes.with_ids() {
+            let failed = ui_state.errors.iter().any(|e| e.node == Some(*id));
             draw_single_node(
-                &painter,
+                painter,
                 &mut draw.other_shapes,
                 &ui_state.world,
+                *id,
                 n,
                 &ui_state.selection,
+                &ui_state.dependencies,
             );
+            if failed {
+                draw_error_badge(painter, &mut draw.other_shapes, &ui_state.world, n);
+            }
         }
 
         painter.extend(draw.lines);
         painter.extend(draw.other_shapes);
 
-        for (i, n) in ui_state.world.nodes.with_ids_mut() {
-            match n.state.render {
-                Some(f) => {
-                    let node_rect = Rect::from_min_size(
-                        n.pos + vec2(10f32, 40f32),
-                        n.prototype.size - vec2(40f32, 70f32),
-                    );
-                    ui.scope_builder(
+        // Widgets edit copies of the state, so a node is only copied out of the undo
+        // snapshots it shares with when it actually changes.
+        let mut edited_states = Vec::new();
+
+        let sizes: Vec<egui::Vec2> = ui_state
+            .world
+            .nodes
+            .iter()
+            .map(|n| ui_state.world.node_size(n))
+            .collect();
+        for ((i, n), size) in ui_state.world.nodes.with_ids().into_iter().zip(sizes) {
+            if let Some(f) = n.state.render {
+                let node_rect =
+                    Rect::from_min_size(n.pos + vec2(10f32, 40f32), size - vec2(40f32, 70f32));
+                let mut state = n.state.state.clone();
+                let changed = ui
+                    .scope_builder(
                         UiBuilder::new().max_rect(node_rect).id_salt(("node", *i)),
-                        |ui| {
-                            if f(ui, &mut n.state.state, n.pos) {
-                                ui_state.texture_outdated = true;
-                            }
-                        },
-                    );
-                    //f(ui, &mut n.state.state, n.pos);
+                        |ui| f(ui, &mut state, n.pos),
+                    )
+                    .inner;
+                if changed {
+                    ui_state.texture_outdated = true;
+                    edited_states.push((*i, state));
                 }
-                None => {}
+                //f(ui, &mut n.state.state, n.pos);
             }
         }
+
+        if let Some((first, _)) = edited_states.first() {
+            ui_state
+                .history
+                .record_merged(MergeKey::NodeState(*first), &ui_state.world);
+        }
+        for (i, state) in &edited_states {
+            ui_state.world.nodes.get_mut(*i).state.state = state.clone();
+        }
+        for (i, _) in &edited_states {
+            basic_nodes::sync_node(&mut ui_state.world, *i);
+        }
     });
 
     ui_state.view_rect = vrect;
@@ -1,6 +1,78 @@
 pub mod add;
 pub mod attribute;
+pub mod color;
 pub mod constant;
 pub mod exp;
+pub mod expression;
+pub mod group_io;
 pub mod image;
+pub mod math;
 pub mod node_tools;
+pub mod noise;
+pub mod with;
+
+use std::collections::HashSet;
+
+use crate::app::{editor_graph::NodeWorld, group, registry::PrototypeRegistry, storage::ID};
+
+pub const GROUP_CATEGORY: &str = "Group";
+
+// Every prototype the editor offers, by category in menu order. Saved graphs are loaded
+// against this set.
+pub fn basic_registry() -> PrototypeRegistry {
+    let mut registry = PrototypeRegistry::default();
+    registry.register("Input", constant::constant_node_prototype());
+    registry.register("Input", color::color_constant_prototype());
+    registry.register("Input", attribute::attribute_prototype());
+    registry.register("Math", add::add_node_prototype());
+    registry.register("Math", exp::exp_prototype());
+    registry.register("Math", math::math_prototype());
+    registry.register("Math", expression::expression_prototype());
+    // Re-evaluates its input under changed attributes rather than producing a value of its own.
+    registry.register("Context", with::with_prototype());
+    registry.register("Noise", noise::noise_prototype());
+    registry.register("Noise", noise::fbm_prototype());
+    registry.register("Color", color::combine_color_prototype());
+    registry.register("Color", color::split_color_prototype());
+    registry.register("Output", image::done_node());
+    registry.register(GROUP_CATEGORY, group_io::group_input_prototype());
+    registry.register(GROUP_CATEGORY, group_io::group_output_prototype());
+    registry.register(
+        "",
+        group::group_prototype(group::GROUP, NodeWorld::default()),
+    );
+    registry
+}
+
+// Brings ports that depend on a node's state in line with it, after the state was edited or
+// loaded.
+pub fn sync_node(world: &mut NodeWorld, node: ID) {
+    group::sync_io_type(world, node);
+    expression::sync_ports(world, node);
+    math::sync_ports(world, node);
+    with::sync_ports(world, node);
+}
+
+// The nodes whose inputs are the result of a graph: Out nodes, or Group Output nodes inside a
+// group.
+pub fn output_nodes(world: &NodeWorld) -> Vec<ID> {
+    world
+        .nodes
+        .with_ids()
+        .into_iter()
+        .filter(|(_, n)| [image::OUT, group_io::GROUP_OUTPUT].contains(&n.key.as_str()))
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+// `outputs` and every node feeding them.
+pub fn used_nodes(world: &NodeWorld, outputs: &[ID]) -> HashSet<ID> {
+    let mut used = HashSet::new();
+    let mut stack = outputs.to_vec();
+    while let Some(node) = stack.pop() {
+        if used.insert(node) {
+            stack.extend(world.upstream_nodes(node));
+        }
+    }
+    used
+}
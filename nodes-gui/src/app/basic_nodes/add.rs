@@ -1,18 +1,23 @@
 use std::collections::HashMap;
 
 use egui::Pos2;
-use rpds::HashTrieMap;
 
 use crate::app::{
-    basic_nodes::node_tools::{get_input, get_state_char, get_state_char_mut},
+    basic_nodes::node_tools::{get_input_f32, get_state_char, get_state_char_mut, required},
     editor_graph::{
-        NodePrototype, NodeState, NodeWorld, PortKindPrototype, PortPrototype, StateValue,
+        EvalContext, EvalError, EvalErrorKind, NodePrototype, NodeState, NodeWorld,
+        PortKindPrototype, PortPrototype, StateValue,
     },
+    interpreter::{BinaryOp, Compiler, Register},
     storage::ID,
+    value::{Value, ValueType},
 };
 
+pub const BINARY_MATH: &str = "binary_math";
+
 pub fn add_node_prototype() -> NodePrototype {
     NodePrototype {
+        key: BINARY_MATH.to_string(),
         name: "Binary Math".to_string(),
         size: egui::vec2(100f32, 80f32),
         ports: vec![
@@ -20,22 +25,26 @@ pub fn add_node_prototype() -> NodePrototype {
                 local_position: egui::vec2(0f32, 50f32),
                 name: "A".to_string(),
                 kind: PortKindPrototype::Input,
+                ty: ValueType::Float,
             },
             PortPrototype {
                 local_position: egui::Vec2 { x: 0f32, y: 60f32 },
                 name: "B".to_string(),
                 kind: PortKindPrototype::Input,
+                ty: ValueType::Float,
             },
             PortPrototype {
                 local_position: egui::vec2(100f32, 40f32),
                 name: "Out".to_string(),
-                kind: PortKindPrototype::Output(add_node_eval),
+                kind: PortKindPrototype::Output(add_node_eval, add_node_compile),
+                ty: ValueType::Float,
             },
         ],
         state_prototype: NodeState {
             state: HashMap::from([("op".to_string(), StateValue::Char('+'))]),
             render: Some(add_render),
         },
+        subgraph: None,
     }
 }
 
@@ -48,6 +57,7 @@ fn add_render(ui: &mut egui::Ui, state: &mut HashMap<String, StateValue>, _: Pos
             ui.selectable_value(val, '+', "Add");
             ui.selectable_value(val, '-', "Sub");
             ui.selectable_value(val, '*', "Mul");
+            ui.selectable_value(val, '/', "Div");
         })
         .response
         .changed()
@@ -57,16 +67,35 @@ fn add_node_eval(
     world: &NodeWorld,
     inputs: &HashMap<String, Option<ID>>,
     state: &HashMap<String, StateValue>,
-    ctx: HashTrieMap<String, f32>,
-) -> Option<f32> {
-    let first_f = get_input("A", world, inputs, &ctx)?;
-    let second_f = get_input("B", world, inputs, &ctx)?;
-    let op = get_state_char("op", state)?;
+    ctx: EvalContext,
+) -> Result<Value, EvalError> {
+    let first_f = get_input_f32("A", world, inputs, &ctx)?;
+    let second_f = get_input_f32("B", world, inputs, &ctx)?;
+    let op = required("op", get_state_char("op", state))?;
 
     match op {
-        '+' => Some(first_f + second_f),
-        '-' => Some(first_f - second_f),
-        '*' => Some(first_f * second_f),
-        _ => None,
+        '+' => Ok(Value::Float(first_f + second_f)),
+        '-' => Ok(Value::Float(first_f - second_f)),
+        '*' => Ok(Value::Float(first_f * second_f)),
+        '/' => Ok(Value::Float(first_f / second_f)),
+        _ => Err(EvalErrorKind::UnknownOp(op.to_string()).into()),
     }
 }
+
+fn add_node_compile(
+    compiler: &mut Compiler,
+    inputs: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+) -> Option<Register> {
+    let op = match get_state_char("op", state)? {
+        '+' => BinaryOp::Add,
+        '-' => BinaryOp::Sub,
+        '*' => BinaryOp::Mul,
+        '/' => BinaryOp::Div,
+        _ => return None,
+    };
+    let first = compiler.input("A", inputs)?;
+    let second = compiler.input("B", inputs)?;
+
+    compiler.binary(op, first, second)
+}
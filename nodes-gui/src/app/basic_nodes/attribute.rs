@@ -1,29 +1,36 @@
 use std::collections::HashMap;
 
 use egui::{Pos2, vec2};
-use rpds::HashTrieMap;
 
 use crate::app::{
-    basic_nodes::node_tools::{get_state_string, get_state_string_mut},
+    basic_nodes::node_tools::{get_attribute, get_state_string, get_state_string_mut, required},
     editor_graph::{
-        NodePrototype, NodeState, NodeWorld, PortKindPrototype, PortPrototype, StateValue,
+        EvalContext, EvalError, NodePrototype, NodeState, NodeWorld, PortKindPrototype,
+        PortPrototype, StateValue,
     },
+    interpreter::{Compiler, Register},
     storage::ID,
+    value::{Value, ValueType},
 };
 
+pub const ATTRIBUTE: &str = "attribute";
+
 pub fn attribute_prototype() -> NodePrototype {
     NodePrototype {
+        key: ATTRIBUTE.to_string(),
         name: "Attr".to_string(),
         ports: vec![PortPrototype {
             local_position: vec2(100f32, 50f32),
             name: "".to_string(),
-            kind: PortKindPrototype::Output(eval_attr),
+            kind: PortKindPrototype::Output(eval_attr, compile_attr),
+            ty: ValueType::Float,
         }],
         state_prototype: NodeState {
             state: HashMap::from([("name".to_string(), StateValue::String("".to_string()))]),
             render: Some(render_attr),
         },
         size: vec2(100f32, 70f32),
+        subgraph: None,
     }
 }
 
@@ -36,9 +43,17 @@ fn eval_attr(
     _: &NodeWorld,
     _: &HashMap<String, Option<ID>>,
     state: &HashMap<String, StateValue>,
-    ctx: HashTrieMap<String, f32>,
-) -> Option<f32> {
+    ctx: EvalContext,
+) -> Result<Value, EvalError> {
+    let name = required("name", get_state_string("name", state))?;
+    get_attribute(name, &ctx)
+}
+
+fn compile_attr(
+    compiler: &mut Compiler,
+    _: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+) -> Option<Register> {
     let name = get_state_string("name", state)?;
-    let val = ctx.get(name)?;
-    Some(*val)
+    Some(compiler.attribute(name))
 }
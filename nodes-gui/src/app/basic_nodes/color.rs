@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use egui::{Pos2, vec2};
+
+use crate::app::{
+    basic_nodes::node_tools::{
+        get_input_color, get_input_f32, get_state_color, get_state_color_mut, required,
+    },
+    editor_graph::{
+        EvalContext, EvalError, NodePrototype, NodeState, NodeWorld, OutputCompileFn,
+        OutputEvaluationFn, PortKindPrototype, PortPrototype, StateValue,
+    },
+    interpreter::{Compiler, Register},
+    storage::ID,
+    value::{Value, ValueType},
+};
+
+pub const COLOR: &str = "color";
+
+pub fn color_constant_prototype() -> NodePrototype {
+    NodePrototype {
+        key: COLOR.to_string(),
+        name: "Color".to_string(),
+        ports: vec![PortPrototype {
+            local_position: vec2(100f32, 30f32),
+            name: "".to_string(),
+            kind: PortKindPrototype::Output(eval_color_constant, compile_color_constant),
+            ty: ValueType::Color,
+        }],
+        state_prototype: NodeState {
+            state: HashMap::from([(
+                "color".to_string(),
+                StateValue::Color([1f32, 1f32, 1f32, 1f32]),
+            )]),
+            render: Some(render_color_constant),
+        },
+        size: vec2(100f32, 80f32),
+        subgraph: None,
+    }
+}
+
+fn eval_color_constant(
+    _: &NodeWorld,
+    _: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+    _: EvalContext,
+) -> Result<Value, EvalError> {
+    Ok(Value::Color(required(
+        "color",
+        get_state_color("color", state),
+    )?))
+}
+
+fn compile_color_constant(
+    compiler: &mut Compiler,
+    _: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+) -> Option<Register> {
+    Some(compiler.constant(Value::Color(get_state_color("color", state)?)))
+}
+
+fn render_color_constant(
+    ui: &mut egui::Ui,
+    state: &mut HashMap<String, StateValue>,
+    _: Pos2,
+) -> bool {
+    let color = get_state_color_mut("color", state).unwrap();
+    ui.color_edit_button_rgba_unmultiplied(color).changed()
+}
+
+pub const COMBINE_COLOR: &str = "combine_color";
+
+pub fn combine_color_prototype() -> NodePrototype {
+    let channel = |name: &str, y: f32| PortPrototype {
+        local_position: vec2(0f32, y),
+        name: name.to_string(),
+        kind: PortKindPrototype::Input,
+        ty: ValueType::Float,
+    };
+
+    NodePrototype {
+        key: COMBINE_COLOR.to_string(),
+        name: "Combine Color".to_string(),
+        ports: vec![
+            channel("R", 30f32),
+            channel("G", 45f32),
+            channel("B", 60f32),
+            channel("A", 75f32),
+            PortPrototype {
+                local_position: vec2(100f32, 30f32),
+                name: "".to_string(),
+                kind: PortKindPrototype::Output(eval_combine_color, compile_combine_color),
+                ty: ValueType::Color,
+            },
+        ],
+        state_prototype: NodeState::default(),
+        size: vec2(100f32, 90f32),
+        subgraph: None,
+    }
+}
+
+// Unlike the other channels, an unconnected alpha doesn't read an attribute but is opaque.
+fn alpha_connected(inputs: &HashMap<String, Option<ID>>) -> bool {
+    matches!(inputs.get("A"), Some(Some(_)))
+}
+
+fn eval_combine_color(
+    world: &NodeWorld,
+    inputs: &HashMap<String, Option<ID>>,
+    _: &HashMap<String, StateValue>,
+    ctx: EvalContext,
+) -> Result<Value, EvalError> {
+    Ok(Value::Color([
+        get_input_f32("R", world, inputs, &ctx)?,
+        get_input_f32("G", world, inputs, &ctx)?,
+        get_input_f32("B", world, inputs, &ctx)?,
+        if alpha_connected(inputs) {
+            get_input_f32("A", world, inputs, &ctx)?
+        } else {
+            1f32
+        },
+    ]))
+}
+
+fn compile_combine_color(
+    compiler: &mut Compiler,
+    inputs: &HashMap<String, Option<ID>>,
+    _: &HashMap<String, StateValue>,
+) -> Option<Register> {
+    let args = [
+        compiler.input_as("R", ValueType::Float, inputs)?,
+        compiler.input_as("G", ValueType::Float, inputs)?,
+        compiler.input_as("B", ValueType::Float, inputs)?,
+        if alpha_connected(inputs) {
+            compiler.input_as("A", ValueType::Float, inputs)?
+        } else {
+            compiler.constant(Value::Float(1f32))
+        },
+    ];
+    compiler.call_value(
+        |args| {
+            Some(Value::Color([
+                args[0].as_f32()?,
+                args[1].as_f32()?,
+                args[2].as_f32()?,
+                args[3].as_f32()?,
+            ]))
+        },
+        &args,
+        Some(ValueType::Color),
+    )
+}
+
+pub const SPLIT_COLOR: &str = "split_color";
+
+pub fn split_color_prototype() -> NodePrototype {
+    let channel =
+        |name: &str, y: f32, eval: OutputEvaluationFn, compile: OutputCompileFn| PortPrototype {
+            local_position: vec2(100f32, y),
+            name: name.to_string(),
+            kind: PortKindPrototype::Output(eval, compile),
+            ty: ValueType::Float,
+        };
+
+    NodePrototype {
+        key: SPLIT_COLOR.to_string(),
+        name: "Split Color".to_string(),
+        ports: vec![
+            PortPrototype {
+                local_position: vec2(0f32, 30f32),
+                name: "Color".to_string(),
+                kind: PortKindPrototype::Input,
+                ty: ValueType::Color,
+            },
+            channel("R", 30f32, eval_split_r, compile_split_r),
+            channel("G", 45f32, eval_split_g, compile_split_g),
+            channel("B", 60f32, eval_split_b, compile_split_b),
+            channel("A", 75f32, eval_split_a, compile_split_a),
+        ],
+        state_prototype: NodeState::default(),
+        size: vec2(100f32, 90f32),
+        subgraph: None,
+    }
+}
+
+fn eval_split_channel(
+    channel: usize,
+    world: &NodeWorld,
+    inputs: &HashMap<String, Option<ID>>,
+    ctx: &EvalContext,
+) -> Result<Value, EvalError> {
+    Ok(Value::Float(
+        get_input_color("Color", world, inputs, ctx)?[channel],
+    ))
+}
+
+fn compile_split_channel(
+    compiler: &mut Compiler,
+    inputs: &HashMap<String, Option<ID>>,
+    extract: fn(&[Value]) -> Option<Value>,
+) -> Option<Register> {
+    let color = compiler.input_as("Color", ValueType::Color, inputs)?;
+    compiler.call_value(extract, &[color], Some(ValueType::Float))
+}
+
+macro_rules! split_channel {
+    ($eval: ident, $compile: ident, $channel: expr) => {
+        fn $eval(
+            world: &NodeWorld,
+            inputs: &HashMap<String, Option<ID>>,
+            _: &HashMap<String, StateValue>,
+            ctx: EvalContext,
+        ) -> Result<Value, EvalError> {
+            eval_split_channel($channel, world, inputs, &ctx)
+        }
+
+        fn $compile(
+            compiler: &mut Compiler,
+            inputs: &HashMap<String, Option<ID>>,
+            _: &HashMap<String, StateValue>,
+        ) -> Option<Register> {
+            compile_split_channel(compiler, inputs, |args| {
+                Some(Value::Float(args[0].as_color()?[$channel]))
+            })
+        }
+    };
+}
+
+split_channel!(eval_split_r, compile_split_r, 0);
+split_channel!(eval_split_g, compile_split_g, 1);
+split_channel!(eval_split_b, compile_split_b, 2);
+split_channel!(eval_split_a, compile_split_a, 3);
@@ -1,27 +1,38 @@
 use std::collections::HashMap;
 
 use egui::{Pos2, Rect, vec2};
-use rpds::HashTrieMap;
 
 use crate::app::{
-    basic_nodes::node_tools::{get_state_f32, get_state_f32_mut},
-    editor_graph::{NodePrototype, NodeState, NodeWorld, PortPrototype, StateValue},
+    basic_nodes::node_tools::{get_state_f32, get_state_f32_mut, required},
+    editor_graph::{
+        EvalContext, EvalError, NodePrototype, NodeState, NodeWorld, PortPrototype, StateValue,
+    },
+    interpreter::{Compiler, Register},
     storage::ID,
+    value::{Value, ValueType},
 };
 
+pub const CONSTANT: &str = "constant";
+
 pub fn constant_node_prototype() -> NodePrototype {
     NodePrototype {
+        key: CONSTANT.to_string(),
         name: "Constant".to_string(),
         ports: vec![PortPrototype {
             local_position: vec2(200f32, 30f32),
             name: "".to_string(),
-            kind: crate::app::editor_graph::PortKindPrototype::Output(evaluate_constant_node),
+            kind: crate::app::editor_graph::PortKindPrototype::Output(
+                evaluate_constant_node,
+                compile_constant_node,
+            ),
+            ty: ValueType::Float,
         }],
         state_prototype: NodeState {
             state: HashMap::from([("val".to_string(), StateValue::Float(1f32))]),
             render: Some(render_constant_node),
         },
         size: vec2(200f32, 100f32),
+        subgraph: None,
     }
 }
 
@@ -29,9 +40,17 @@ fn evaluate_constant_node(
     _: &NodeWorld,
     _: &HashMap<String, Option<ID>>,
     state: &HashMap<String, StateValue>,
-    _: HashTrieMap<String, f32>,
-) -> Option<f32> {
-    get_state_f32("val", state)
+    _: EvalContext,
+) -> Result<Value, EvalError> {
+    Ok(Value::Float(required("val", get_state_f32("val", state))?))
+}
+
+fn compile_constant_node(
+    compiler: &mut Compiler,
+    _: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+) -> Option<Register> {
+    Some(compiler.constant(Value::Float(get_state_f32("val", state)?)))
 }
 
 fn render_constant_node(
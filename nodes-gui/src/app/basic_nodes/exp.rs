@@ -3,25 +3,35 @@ use egui::vec2;
 use crate::app::{
     basic_nodes::node_tools,
     editor_graph::{NodePrototype, NodeState, PortPrototype},
+    value::ValueType,
 };
 
+pub const EXP: &str = "exp";
+
 pub fn exp_prototype() -> NodePrototype {
     NodePrototype {
+        key: EXP.to_string(),
         name: "Exp".to_string(),
         ports: vec![
             PortPrototype {
                 local_position: vec2(0f32, 20f32),
                 name: "Inp".to_string(),
                 kind: crate::app::editor_graph::PortKindPrototype::Input,
+                ty: ValueType::Float,
             },
             PortPrototype {
                 local_position: vec2(50f32, 20f32),
                 name: "".to_string(),
-                kind: crate::app::editor_graph::PortKindPrototype::Output(exp_node_eval),
+                kind: crate::app::editor_graph::PortKindPrototype::Output(
+                    exp_node_eval,
+                    exp_node_compile,
+                ),
+                ty: ValueType::Float,
             },
         ],
         state_prototype: NodeState::default(),
         size: vec2(50f32, 50f32),
+        subgraph: None,
     }
 }
 
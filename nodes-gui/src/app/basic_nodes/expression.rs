@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use egui::{Color32, Pos2, vec2};
+
+use crate::app::{
+    basic_nodes::node_tools::{
+        get_input_f32, get_state_string, get_state_string_mut, required, sync_inputs,
+    },
+    editor_graph::{
+        EvalContext, EvalError, EvalErrorKind, NodePrototype, NodeState, NodeWorld,
+        PortKindPrototype, PortPrototype, StateValue,
+    },
+    expr::{self, Expr, Op},
+    interpreter::{BinaryOp, Compiler, Register},
+    storage::ID,
+    value::{Value, ValueType},
+};
+
+pub const EXPRESSION: &str = "expression";
+
+const WIDTH: f32 = 200f32;
+
+// Has no inputs until `sync_ports` gives it one per variable of its formula.
+pub fn expression_prototype() -> NodePrototype {
+    NodePrototype {
+        key: EXPRESSION.to_string(),
+        name: "Expression".to_string(),
+        ports: vec![output_port()],
+        state_prototype: NodeState {
+            state: HashMap::from([("expr".to_string(), StateValue::String("x".to_string()))]),
+            render: Some(render_expression),
+        },
+        size: size(0),
+        subgraph: None,
+    }
+}
+
+fn output_port() -> PortPrototype {
+    PortPrototype {
+        local_position: vec2(WIDTH, 30f32),
+        name: "".to_string(),
+        kind: PortKindPrototype::Output(eval_expression, compile_expression),
+        ty: ValueType::Float,
+    }
+}
+
+fn size(inputs: usize) -> egui::Vec2 {
+    vec2(WIDTH, 120f32.max(70f32 + 15f32 * inputs as f32))
+}
+
+fn render_expression(ui: &mut egui::Ui, state: &mut HashMap<String, StateValue>, _: Pos2) -> bool {
+    let text = get_state_string_mut("expr", state).unwrap();
+    let changed = ui.text_edit_singleline(text).changed();
+    if let Err(e) = expr::parse(text) {
+        ui.colored_label(Color32::RED, e.to_string());
+    }
+    changed
+}
+
+fn parsed(state: &HashMap<String, StateValue>) -> Option<Expr> {
+    expr::parse(get_state_string("expr", state)?).ok()
+}
+
+// Gives an Expression node one input per variable of its formula, in order of appearance.
+// Unconnected ones read the attribute of the same name. While the formula doesn't parse, the
+// ports stay as they are so links survive typing.
+pub fn sync_ports(world: &mut NodeWorld, node: ID) {
+    let n = world.nodes.get(node);
+    if n.key != EXPRESSION {
+        return;
+    }
+    let Some(expr) = parsed(&n.state.state) else {
+        return;
+    };
+    let vars = expr.variables();
+    let inputs: Vec<_> = vars
+        .iter()
+        .map(|name| (name.as_str(), ValueType::Float))
+        .collect();
+    sync_inputs(world, node, &inputs, size(vars.len()));
+}
+
+fn eval_expression(
+    world: &NodeWorld,
+    inputs: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+    ctx: EvalContext,
+) -> Result<Value, EvalError> {
+    let text = required("expr", get_state_string("expr", state))?;
+    let expr = expr::parse(text).map_err(EvalErrorKind::InvalidFormula)?;
+    let val = expr.eval(&mut |name| get_input_f32(name, world, inputs, &ctx))?;
+    Ok(Value::Float(val))
+}
+
+fn compile_expression(
+    compiler: &mut Compiler,
+    inputs: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+) -> Option<Register> {
+    compile(&parsed(state)?, compiler, inputs)
+}
+
+fn compile(
+    expr: &Expr,
+    compiler: &mut Compiler,
+    inputs: &HashMap<String, Option<ID>>,
+) -> Option<Register> {
+    match expr {
+        Expr::Number(n) => Some(compiler.constant(Value::Float(*n))),
+        Expr::Var(name) => compiler.input_as(name, ValueType::Float, inputs),
+        Expr::Neg(e) => {
+            let e = compile(e, compiler, inputs)?;
+            compiler.call(|a| -a[0], &[e])
+        }
+        Expr::Binary(op, a, b) => {
+            let a = compile(a, compiler, inputs)?;
+            let b = compile(b, compiler, inputs)?;
+            match op {
+                Op::Add => compiler.binary(BinaryOp::Add, a, b),
+                Op::Sub => compiler.binary(BinaryOp::Sub, a, b),
+                Op::Mul => compiler.binary(BinaryOp::Mul, a, b),
+                Op::Div => compiler.binary(BinaryOp::Div, a, b),
+                Op::Rem => compiler.call(|a| Op::Rem.apply(a[0], a[1]), &[a, b]),
+                Op::Pow => compiler.call(|a| Op::Pow.apply(a[0], a[1]), &[a, b]),
+            }
+        }
+        Expr::Call(f, args) => {
+            let args: Option<Vec<Register>> =
+                args.iter().map(|a| compile(a, compiler, inputs)).collect();
+            compiler.call(f.function(), &args?)
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use egui::{Pos2, vec2};
+
+use crate::app::{
+    basic_nodes::node_tools::{get_state_string, get_state_string_mut, required},
+    editor_graph::{
+        EvalContext, EvalError, EvalErrorKind, NodePrototype, NodeState, NodeWorld,
+        PortKindPrototype, PortPrototype, StateValue,
+    },
+    group,
+    interpreter::{Compiler, Register},
+    storage::ID,
+    value::{Value, ValueType},
+};
+
+pub const GROUP_INPUT: &str = "group_input";
+pub const GROUP_OUTPUT: &str = "group_output";
+
+// Inside a group, yields the value of the group node's input with the same name.
+pub fn group_input_prototype() -> NodePrototype {
+    NodePrototype {
+        key: GROUP_INPUT.to_string(),
+        name: "Group Input".to_string(),
+        ports: vec![PortPrototype {
+            local_position: vec2(160f32, 30f32),
+            name: "".to_string(),
+            kind: PortKindPrototype::Output(eval_group_input, compile_group_input),
+            ty: ValueType::Float,
+        }],
+        state_prototype: io_state("in"),
+        size: vec2(160f32, 110f32),
+        subgraph: None,
+    }
+}
+
+// Inside a group, whatever is connected here becomes the group node's output of this name.
+pub fn group_output_prototype() -> NodePrototype {
+    NodePrototype {
+        key: GROUP_OUTPUT.to_string(),
+        name: "Group Output".to_string(),
+        ports: vec![PortPrototype {
+            local_position: vec2(0f32, 30f32),
+            name: "".to_string(),
+            kind: PortKindPrototype::Input,
+            ty: ValueType::Float,
+        }],
+        state_prototype: io_state("out"),
+        size: vec2(160f32, 110f32),
+        subgraph: None,
+    }
+}
+
+fn io_state(name: &str) -> NodeState {
+    NodeState {
+        state: HashMap::from([
+            ("name".to_string(), StateValue::String(name.to_string())),
+            (
+                "type".to_string(),
+                StateValue::String(ValueType::Float.name().to_string()),
+            ),
+        ]),
+        render: Some(render_io),
+    }
+}
+
+pub fn io_name(state: &HashMap<String, StateValue>) -> Option<&str> {
+    get_state_string("name", state).map(String::as_str)
+}
+
+pub fn io_type(state: &HashMap<String, StateValue>) -> Option<ValueType> {
+    ValueType::from_name(get_state_string("type", state)?)
+}
+
+// Changing the type only edits the state, `group::sync_io_type` then retypes the port.
+fn render_io(ui: &mut egui::Ui, state: &mut HashMap<String, StateValue>, _: Pos2) -> bool {
+    let mut changed = ui
+        .text_edit_singleline(get_state_string_mut("name", state).unwrap())
+        .changed();
+
+    let ty = get_state_string_mut("type", state).unwrap();
+    egui::ComboBox::from_id_salt("type")
+        .selected_text(ty.as_str())
+        .show_ui(ui, |ui| {
+            for option in ValueType::ALL {
+                changed |= ui
+                    .selectable_value(ty, option.name().to_string(), option.name())
+                    .changed();
+            }
+        });
+    changed
+}
+
+fn eval_group_input(
+    _: &NodeWorld,
+    _: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+    ctx: EvalContext,
+) -> Result<Value, EvalError> {
+    let name = required("name", io_name(state))?;
+    ctx.get(&group::input_key(name))
+        .copied()
+        .ok_or_else(|| EvalErrorKind::OutsideGroup(name.to_string()).into())
+}
+
+fn compile_group_input(
+    compiler: &mut Compiler,
+    _: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+) -> Option<Register> {
+    compiler.group_input(io_name(state)?)
+}
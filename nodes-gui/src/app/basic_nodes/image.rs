@@ -1,21 +1,58 @@
 use std::collections::HashMap;
 
-use egui::vec2;
+use egui::{Pos2, vec2};
 
-use crate::app::editor_graph::{NodePrototype, NodeState, PortPrototype};
+use crate::app::{
+    basic_nodes::node_tools::{get_state_f32, get_state_f32_mut},
+    editor_graph::{NodePrototype, NodeState, PortPrototype, StateValue},
+    render::{MAX_RESOLUTION, Resolution},
+    value::ValueType,
+};
+
+pub const OUT: &str = "out";
 
 pub fn done_node() -> NodePrototype {
     NodePrototype {
+        key: OUT.to_string(),
         name: "Out".to_string(),
         ports: vec![PortPrototype {
             local_position: vec2(0f32, 10f32),
             name: "Inp".to_string(),
             kind: crate::app::editor_graph::PortKindPrototype::Input,
+            ty: ValueType::Color,
         }],
         state_prototype: NodeState {
-            state: HashMap::new(),
-            render: None,
+            state: HashMap::from([
+                ("width".to_string(), StateValue::Float(128f32)),
+                ("height".to_string(), StateValue::Float(128f32)),
+            ]),
+            render: Some(render_done_node),
         },
-        size: vec2(50f32, 50f32),
+        size: vec2(150f32, 110f32),
+        subgraph: None,
+    }
+}
+
+// The size the Out node with this state renders at.
+pub fn output_resolution(state: &HashMap<String, StateValue>) -> Resolution {
+    let side = |name| get_state_f32(name, state).unwrap_or(128f32) as usize;
+    Resolution::new(side("width"), side("height"))
+}
+
+fn render_done_node(ui: &mut egui::Ui, state: &mut HashMap<String, StateValue>, _: Pos2) -> bool {
+    let mut changed = false;
+    for name in ["width", "height"] {
+        ui.horizontal(|ui| {
+            ui.label(name);
+            let side = get_state_f32_mut(name, state).unwrap();
+            changed |= ui
+                .add(
+                    egui::DragValue::new(side)
+                        .range(1..=MAX_RESOLUTION)
+                        .speed(1f32),
+                )
+                .changed();
+        });
     }
+    changed
 }
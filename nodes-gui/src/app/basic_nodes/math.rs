@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use egui::{Pos2, vec2};
+
+use crate::app::{
+    basic_nodes::node_tools::{self, get_state_string, get_state_string_mut, sync_inputs},
+    editor_graph::{
+        NodePrototype, NodeState, NodeWorld, PortKindPrototype, PortPrototype, StateValue,
+    },
+    smoother_step,
+    storage::ID,
+    value::ValueType,
+};
+
+pub const MATH: &str = "math";
+
+const WIDTH: f32 = 140f32;
+
+pub fn math_prototype() -> NodePrototype {
+    let (op, inputs) = MATH_OPS[0];
+    NodePrototype {
+        key: MATH.to_string(),
+        name: "Math".to_string(),
+        ports: inputs
+            .iter()
+            .enumerate()
+            .map(|(i, name)| PortPrototype {
+                local_position: vec2(0f32, 50f32 + 15f32 * i as f32),
+                name: name.to_string(),
+                kind: PortKindPrototype::Input,
+                ty: ValueType::Float,
+            })
+            .chain([PortPrototype {
+                local_position: vec2(WIDTH, 30f32),
+                name: "".to_string(),
+                kind: PortKindPrototype::Output(math_eval, math_compile),
+                ty: ValueType::Float,
+            }])
+            .collect(),
+        state_prototype: NodeState {
+            state: HashMap::from([("op".to_string(), StateValue::String(op.to_string()))]),
+            render: Some(render_math),
+        },
+        size: size(inputs.len()),
+        subgraph: None,
+    }
+}
+
+fn size(inputs: usize) -> egui::Vec2 {
+    vec2(WIDTH, 90f32.max(70f32 + 15f32 * inputs as f32))
+}
+
+fn render_math(ui: &mut egui::Ui, state: &mut HashMap<String, StateValue>, _: Pos2) -> bool {
+    let val = get_state_string_mut("op", state).unwrap();
+    egui::containers::ComboBox::from_id_salt("math_op")
+        .selected_text(val.as_str())
+        .show_ui(ui, |ui| {
+            for (op, _) in MATH_OPS {
+                ui.selectable_value(val, op.to_string(), *op);
+            }
+        })
+        .response
+        .changed()
+}
+
+// Gives a Math node the inputs of its current op.
+pub fn sync_ports(world: &mut NodeWorld, node: ID) {
+    let n = world.nodes.get(node);
+    if n.key != MATH {
+        return;
+    }
+    let Some(op) = get_state_string("op", &n.state.state) else {
+        return;
+    };
+    let Some((_, inputs)) = MATH_OPS.iter().find(|(name, _)| name == op) else {
+        return;
+    };
+    let inputs: Vec<_> = inputs
+        .iter()
+        .map(|name| (*name, ValueType::Float))
+        .collect();
+    sync_inputs(world, node, &inputs, size(inputs.len()));
+}
+
+pub fn add(a: f32, b: f32) -> f32 {
+    a + b
+}
+
+pub fn sub(a: f32, b: f32) -> f32 {
+    a - b
+}
+
+pub fn mul(a: f32, b: f32) -> f32 {
+    a * b
+}
+
+pub fn div(a: f32, b: f32) -> f32 {
+    a / b
+}
+
+// Floored like GLSL's `mod`, so the result has the sign of `b`. Expression `%` uses this too,
+// unlike Rust's `%`, which truncates.
+pub fn modulo(a: f32, b: f32) -> f32 {
+    a - b * (a / b).floor()
+}
+
+pub fn pow(base: f32, exponent: f32) -> f32 {
+    base.powf(exponent)
+}
+
+pub fn min(a: f32, b: f32) -> f32 {
+    a.min(b)
+}
+
+pub fn max(a: f32, b: f32) -> f32 {
+    a.max(b)
+}
+
+pub fn abs(x: f32) -> f32 {
+    x.abs()
+}
+
+// Unlike `f32::signum`, 0 at 0.
+fn sign(x: f32) -> f32 {
+    if x == 0f32 { 0f32 } else { x.signum() }
+}
+
+pub fn floor(x: f32) -> f32 {
+    x.floor()
+}
+
+fn ceil(x: f32) -> f32 {
+    x.ceil()
+}
+
+pub fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+fn sqrt(x: f32) -> f32 {
+    x.sqrt()
+}
+
+// Natural logarithm.
+fn log(x: f32) -> f32 {
+    x.ln()
+}
+
+pub fn sin(x: f32) -> f32 {
+    x.sin()
+}
+
+pub fn cos(x: f32) -> f32 {
+    x.cos()
+}
+
+fn tan(x: f32) -> f32 {
+    x.tan()
+}
+
+fn atan2(y: f32, x: f32) -> f32 {
+    y.atan2(x)
+}
+
+// Not `f32::clamp`, which panics when the bounds are the wrong way around.
+pub fn clamp(x: f32, min: f32, max: f32) -> f32 {
+    x.max(min).min(max)
+}
+
+pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn step(edge: f32, x: f32) -> f32 {
+    if x < edge { 0f32 } else { 1f32 }
+}
+
+// Where `x` is between the edges, clamped to 0..1.
+fn ramp(edge0: f32, edge1: f32, x: f32) -> f32 {
+    clamp((x - edge0) / (edge1 - edge0), 0f32, 1f32)
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ramp(edge0, edge1, x);
+    t * t * (3f32 - 2f32 * t)
+}
+
+fn smootherstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    smoother_step(ramp(edge0, edge1, x))
+}
+
+// Maps `x` from one range to another without clamping.
+fn remap(x: f32, from_min: f32, from_max: f32, to_min: f32, to_max: f32) -> f32 {
+    lerp(to_min, to_max, (x - from_min) / (from_max - from_min))
+}
+
+node_tools::node_evaluator! {math, "op" {
+    add(A, B),
+    sub(A, B),
+    mul(A, B),
+    div(A, B),
+    modulo(A, B),
+    pow(Base, Exponent),
+    min(A, B),
+    max(A, B),
+    abs(X),
+    sign(X),
+    floor(X),
+    ceil(X),
+    fract(X),
+    sqrt(X),
+    log(X),
+    sin(X),
+    cos(X),
+    tan(X),
+    atan2(Y, X),
+    clamp(X, Min, Max),
+    lerp(A, B, T),
+    step(Edge, X),
+    smoothstep(Edge0, Edge1, X),
+    smootherstep(Edge0, Edge1, X),
+    remap(X, FromMin, FromMax, ToMin, ToMax),
+}}
@@ -1,10 +1,12 @@
 use std::collections::HashMap;
 
-use rpds::HashTrieMap;
-
 use crate::app::{
-    editor_graph::{NodeWorld, StateValue},
+    editor_graph::{
+        self, EvalContext, EvalError, EvalErrorKind, Layout, NodeWorld, PortKindPrototype,
+        PortPrototype, StateValue,
+    },
     storage::ID,
+    value::{Value, ValueType},
 };
 
 #[macro_export]
@@ -15,15 +17,85 @@ macro_rules! node_evaluator {
            fn [<$f_name _eval>]
 
         (
-            world: &crate::app::editor_graph::NodeWorld,
-            inputs: &std::collections::HashMap<String, Option<crate::app::storage::ID>>,
-            _state: &std::collections::HashMap<String, crate::app::editor_graph::StateValue>,
-            ctx: rpds::HashTrieMap<String, f32>,
-        ) -> Option<f32> {
-            Some($f_name ($(crate::app::basic_nodes::node_tools::get_input(stringify!($arg_name), world, inputs, &ctx)?,)*))
+            world: &$crate::app::editor_graph::NodeWorld,
+            inputs: &std::collections::HashMap<String, Option<$crate::app::storage::ID>>,
+            _state: &std::collections::HashMap<String, $crate::app::editor_graph::StateValue>,
+            ctx: $crate::app::editor_graph::EvalContext,
+        ) -> Result<$crate::app::value::Value, $crate::app::editor_graph::EvalError> {
+            Ok($crate::app::value::Value::Float($f_name ($($crate::app::basic_nodes::node_tools::get_input_f32(stringify!($arg_name), world, inputs, &ctx)?,)*)))
+        }
+
+           fn [<$f_name _compile>]
+
+        (
+            compiler: &mut $crate::app::interpreter::Compiler,
+            inputs: &std::collections::HashMap<String, Option<$crate::app::storage::ID>>,
+            _state: &std::collections::HashMap<String, $crate::app::editor_graph::StateValue>,
+        ) -> Option<$crate::app::interpreter::Register> {
+            let args = [$(compiler.input_as(stringify!($arg_name), $crate::app::value::ValueType::Float, inputs)?,)*];
+            compiler.call(
+                |args| {
+                    let &[$([<arg_ $arg_name:snake>],)*] = args else {
+                        unreachable!()
+                    };
+                    $f_name ($([<arg_ $arg_name:snake>],)*)
+                },
+                &args,
+            )
         }
     }
     };
+    // A node whose state string `$op_key` picks one of several functions, each with inputs of
+    // its own. Also generates `<NAME>_OPS`, every op with its input names in the order given,
+    // for choosing the op and laying out ports.
+    ($f_name: ident, $op_key: literal { $($op: ident ($($arg_name: ident),*)),* $(,)? }) => {
+
+        paste::paste! {
+            const [<$f_name:upper _OPS>]: &[(&str, &[&str])] =
+                &[$((stringify!($op), &[$(stringify!($arg_name)),*])),*];
+
+            fn [<$f_name _eval>]
+            (
+                world: &$crate::app::editor_graph::NodeWorld,
+                inputs: &std::collections::HashMap<String, Option<$crate::app::storage::ID>>,
+                state: &std::collections::HashMap<String, $crate::app::editor_graph::StateValue>,
+                ctx: $crate::app::editor_graph::EvalContext,
+            ) -> Result<$crate::app::value::Value, $crate::app::editor_graph::EvalError> {
+                let op = $crate::app::basic_nodes::node_tools::required(
+                    $op_key,
+                    $crate::app::basic_nodes::node_tools::get_state_string($op_key, state),
+                )?;
+                match op.as_str() {
+                    $(stringify!($op) => Ok($crate::app::value::Value::Float($op ($($crate::app::basic_nodes::node_tools::get_input_f32(stringify!($arg_name), world, inputs, &ctx)?,)*))),)*
+                    _ => Err($crate::app::editor_graph::EvalErrorKind::UnknownOp(op.clone()).into()),
+                }
+            }
+
+            fn [<$f_name _compile>]
+            (
+                compiler: &mut $crate::app::interpreter::Compiler,
+                inputs: &std::collections::HashMap<String, Option<$crate::app::storage::ID>>,
+                state: &std::collections::HashMap<String, $crate::app::editor_graph::StateValue>,
+            ) -> Option<$crate::app::interpreter::Register> {
+                let op = $crate::app::basic_nodes::node_tools::get_state_string($op_key, state)?;
+                match op.as_str() {
+                    $(stringify!($op) => {
+                        let args = [$(compiler.input_as(stringify!($arg_name), $crate::app::value::ValueType::Float, inputs)?,)*];
+                        compiler.call(
+                            |args| {
+                                let &[$([<arg_ $arg_name:snake>],)*] = args else {
+                                    unreachable!()
+                                };
+                                $op ($([<arg_ $arg_name:snake>],)*)
+                            },
+                            &args,
+                        )
+                    })*
+                    _ => None,
+                }
+            }
+        }
+    };
 }
 
 pub(super) use node_evaluator;
@@ -32,14 +104,49 @@ pub fn get_input(
     name: &str,
     world: &NodeWorld,
     inputs: &HashMap<String, Option<ID>>,
-    ctx: &HashTrieMap<String, f32>,
-) -> Option<f32> {
-    match inputs.get(name) {
-        Some(Some(id)) => return world.evaluate_output_port(*id, ctx.clone()),
-        _ => {}
-    };
+    ctx: &EvalContext,
+) -> Result<Value, EvalError> {
+    if let Some(Some(id)) = inputs.get(name) {
+        return world.evaluate_output_port(*id, ctx.clone());
+    }
+
+    get_attribute(name, ctx)
+}
 
-    ctx.get(name).copied()
+pub fn get_attribute(name: &str, ctx: &EvalContext) -> Result<Value, EvalError> {
+    ctx.get(name)
+        .copied()
+        .ok_or_else(|| EvalErrorKind::MissingAttribute(name.to_string()).into())
+}
+
+pub fn get_input_f32(
+    name: &str,
+    world: &NodeWorld,
+    inputs: &HashMap<String, Option<ID>>,
+    ctx: &EvalContext,
+) -> Result<f32, EvalError> {
+    match editor_graph::convert(get_input(name, world, inputs, ctx)?, ValueType::Float)? {
+        Value::Float(f) => Ok(f),
+        _ => unreachable!(),
+    }
+}
+
+pub fn get_input_color(
+    name: &str,
+    world: &NodeWorld,
+    inputs: &HashMap<String, Option<ID>>,
+    ctx: &EvalContext,
+) -> Result<[f32; 4], EvalError> {
+    match editor_graph::convert(get_input(name, world, inputs, ctx)?, ValueType::Color)? {
+        Value::Color(c) => Ok(c),
+        _ => unreachable!(),
+    }
+}
+
+// For evaluation functions reading their state, e.g. `required("op", get_state_char("op",
+// state))?`.
+pub fn required<T>(name: &str, val: Option<T>) -> Result<T, EvalError> {
+    val.ok_or_else(|| EvalError::invalid_state(name))
 }
 
 pub fn get_state_char(name: &str, state: &HashMap<String, StateValue>) -> Option<char> {
@@ -101,3 +208,62 @@ pub fn get_state_string_mut<'a>(
         _ => None,
     }
 }
+
+pub fn get_state_color(name: &str, state: &HashMap<String, StateValue>) -> Option<[f32; 4]> {
+    let val = state.get(name)?;
+    match val {
+        StateValue::Color(c) => Some(*c),
+        _ => None,
+    }
+}
+
+pub fn get_state_color_mut<'a>(
+    name: &str,
+    state: &'a mut HashMap<String, StateValue>,
+) -> Option<&'a mut [f32; 4]> {
+    let val = state.get_mut(name)?;
+    match val {
+        StateValue::Color(c) => Some(c),
+        _ => None,
+    }
+}
+
+// Replaces the inputs of a node whose ports depend on its state with `inputs`, down its left
+// edge, and resizes it. Inputs that keep their name keep their links if their type allows.
+pub fn sync_inputs(
+    world: &mut NodeWorld,
+    node: ID,
+    inputs: &[(&str, ValueType)],
+    size: egui::Vec2,
+) {
+    let n = world.nodes.get(node);
+    let current = n
+        .ports
+        .iter()
+        .filter(|p| world.ports.get(**p).connection_kind.is_input())
+        .map(|p| world.port_info(*p))
+        .map(|p| (p.name.as_str(), p.ty));
+    if current.eq(inputs.iter().copied()) && world.node_size(n) == size {
+        return;
+    }
+
+    let outputs: Vec<PortPrototype> = n
+        .ports
+        .iter()
+        .map(|p| world.port_info(*p))
+        .filter(|p| !matches!(p.kind, PortKindPrototype::Input))
+        .cloned()
+        .collect();
+    let ports = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, (name, ty))| PortPrototype {
+            local_position: egui::vec2(0f32, 50f32 + 15f32 * i as f32),
+            name: name.to_string(),
+            kind: PortKindPrototype::Input,
+            ty: *ty,
+        })
+        .chain(outputs)
+        .collect();
+    world.set_layout(node, Some(Layout { ports, size }));
+}
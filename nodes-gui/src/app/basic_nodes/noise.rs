@@ -0,0 +1,464 @@
+// Seeded 2D noise. The same seed and coordinates always give the same value, on every
+// platform, so renders are reproducible.
+
+use std::{
+    collections::HashMap,
+    f32::consts::{FRAC_1_SQRT_2, SQRT_2},
+};
+
+use egui::{Pos2, vec2};
+
+use crate::app::{
+    basic_nodes::node_tools::{
+        get_input_f32, get_state_f32, get_state_f32_mut, get_state_string, get_state_string_mut,
+        required,
+    },
+    editor_graph::{
+        EvalContext, EvalError, NodePrototype, NodeState, NodeWorld, PortKindPrototype,
+        PortPrototype, StateValue,
+    },
+    interpreter::{Compiler, Register},
+    smoother_step,
+    storage::ID,
+    value::{Value, ValueType},
+};
+
+pub const NOISE: &str = "noise";
+pub const FBM: &str = "fbm";
+
+const MAX_OCTAVES: usize = 12;
+// Seeds are stored as f32 state, which holds every integer only up to 2^24. Larger seeds
+// would round onto their neighbours and several of them would give the same noise.
+const MAX_SEED: f32 = 16_777_216f32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoiseKind {
+    Value,
+    Gradient,
+    Simplex,
+    WorleyF1,
+    WorleyF2,
+    WorleyF2MinusF1,
+}
+
+impl NoiseKind {
+    pub const ALL: [NoiseKind; 6] = [
+        NoiseKind::Value,
+        NoiseKind::Gradient,
+        NoiseKind::Simplex,
+        NoiseKind::WorleyF1,
+        NoiseKind::WorleyF2,
+        NoiseKind::WorleyF2MinusF1,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NoiseKind::Value => "Value",
+            NoiseKind::Gradient => "Gradient",
+            NoiseKind::Simplex => "Simplex",
+            NoiseKind::WorleyF1 => "Worley F1",
+            NoiseKind::WorleyF2 => "Worley F2",
+            NoiseKind::WorleyF2MinusF1 => "Worley F2-F1",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<NoiseKind> {
+        NoiseKind::ALL.into_iter().find(|k| k.name() == name)
+    }
+
+    // Value, gradient and simplex noise lie in -1..1, Worley distances start at 0 and rarely
+    // exceed 1.
+    pub fn sample(self, x: f32, y: f32, seed: u32) -> f32 {
+        match self {
+            NoiseKind::Value => value_noise(x, y, seed),
+            NoiseKind::Gradient => gradient_noise(x, y, seed),
+            NoiseKind::Simplex => simplex_noise(x, y, seed),
+            NoiseKind::WorleyF1 => worley(x, y, seed).0,
+            NoiseKind::WorleyF2 => worley(x, y, seed).1,
+            NoiseKind::WorleyF2MinusF1 => {
+                let (f1, f2) = worley(x, y, seed);
+                f2 - f1
+            }
+        }
+    }
+}
+
+// Sums `octaves` layers of noise, each `lacunarity` times the frequency and `gain` times the
+// amplitude of the one before, normalized so the range matches a single layer.
+pub fn fbm(
+    kind: NoiseKind,
+    x: f32,
+    y: f32,
+    seed: u32,
+    octaves: usize,
+    lacunarity: f32,
+    gain: f32,
+) -> f32 {
+    let (mut sum, mut total) = (0f32, 0f32);
+    let (mut frequency, mut amplitude) = (1f32, 1f32);
+    for octave in 0..octaves.clamp(1, MAX_OCTAVES) {
+        // Each layer gets its own seed, else their features line up at the origin.
+        let seed = seed.wrapping_add((octave as u32).wrapping_mul(0x9e37_79b9));
+        sum += amplitude * kind.sample(x * frequency, y * frequency, seed);
+        total += amplitude;
+        frequency *= lacunarity;
+        amplitude *= gain;
+    }
+    if total == 0f32 { 0f32 } else { sum / total }
+}
+
+fn hash(seed: u32, x: i32, y: i32) -> u32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(0x27d4_eb2d);
+    h = (h ^ (h >> 15)).wrapping_mul(0x85eb_ca6b);
+    h ^= (y as u32).wrapping_mul(0x1656_67b1);
+    h = (h ^ (h >> 13)).wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+// In 0..1.
+fn unit(h: u32) -> f32 {
+    (h >> 8) as f32 / (1u32 << 24) as f32
+}
+
+fn gradient(h: u32) -> (f32, f32) {
+    const DIRECTIONS: [(f32, f32); 8] = [
+        (1f32, 0f32),
+        (-1f32, 0f32),
+        (0f32, 1f32),
+        (0f32, -1f32),
+        (FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+        (-FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+        (FRAC_1_SQRT_2, -FRAC_1_SQRT_2),
+        (-FRAC_1_SQRT_2, -FRAC_1_SQRT_2),
+    ];
+    DIRECTIONS[(h & 7) as usize]
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// The lattice cell containing (x, y) and the position within it.
+fn cell(x: f32, y: f32) -> (i32, i32, f32, f32) {
+    let (fx, fy) = (x.floor(), y.floor());
+    (fx as i32, fy as i32, x - fx, y - fy)
+}
+
+fn value_noise(x: f32, y: f32, seed: u32) -> f32 {
+    let (ix, iy, fx, fy) = cell(x, y);
+    let corner = |dx, dy| unit(hash(seed, ix + dx, iy + dy)) * 2f32 - 1f32;
+    let (u, v) = (smoother_step(fx), smoother_step(fy));
+    lerp(
+        lerp(corner(0, 0), corner(1, 0), u),
+        lerp(corner(0, 1), corner(1, 1), u),
+        v,
+    )
+}
+
+// Perlin's improved noise.
+fn gradient_noise(x: f32, y: f32, seed: u32) -> f32 {
+    let (ix, iy, fx, fy) = cell(x, y);
+    let corner = |dx: i32, dy: i32| {
+        let (gx, gy) = gradient(hash(seed, ix + dx, iy + dy));
+        gx * (fx - dx as f32) + gy * (fy - dy as f32)
+    };
+    let (u, v) = (smoother_step(fx), smoother_step(fy));
+    // Unit gradients reach at most 1/sqrt(2).
+    SQRT_2
+        * lerp(
+            lerp(corner(0, 0), corner(1, 0), u),
+            lerp(corner(0, 1), corner(1, 1), u),
+            v,
+        )
+}
+
+fn simplex_noise(x: f32, y: f32, seed: u32) -> f32 {
+    const SKEW: f32 = 0.366_025_42; // (sqrt(3) - 1) / 2
+    const UNSKEW: f32 = 0.211_324_87; // (3 - sqrt(3)) / 6
+
+    let s = (x + y) * SKEW;
+    let (i, j) = ((x + s).floor(), (y + s).floor());
+    let t = (i + j) * UNSKEW;
+    let (x0, y0) = (x - (i - t), y - (j - t));
+    let (i, j) = (i as i32, j as i32);
+    // Which of the two triangles of the skewed cell the point is in.
+    let (i1, j1) = if x0 > y0 { (1, 0) } else { (0, 1) };
+
+    let corner = |x: f32, y: f32, di: i32, dj: i32| {
+        let t = 0.5f32 - x * x - y * y;
+        if t < 0f32 {
+            return 0f32;
+        }
+        let (gx, gy) = gradient(hash(seed, i + di, j + dj));
+        t.powi(4) * (gx * x + gy * y)
+    };
+    let n0 = corner(x0, y0, 0, 0);
+    let n1 = corner(x0 - i1 as f32 + UNSKEW, y0 - j1 as f32 + UNSKEW, i1, j1);
+    let n2 = corner(x0 - 1f32 + 2f32 * UNSKEW, y0 - 1f32 + 2f32 * UNSKEW, 1, 1);
+    // Scales the largest possible sum to about 1.
+    99.2f32 * (n0 + n1 + n2)
+}
+
+// Distances to the nearest and second nearest of one random point per lattice cell.
+fn worley(x: f32, y: f32, seed: u32) -> (f32, f32) {
+    let (ix, iy, fx, fy) = cell(x, y);
+    let (mut f1, mut f2) = (f32::INFINITY, f32::INFINITY);
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            let (cx, cy) = (ix + dx, iy + dy);
+            let px = dx as f32 + unit(hash(seed, cx, cy)) - fx;
+            let py = dy as f32 + unit(hash(seed ^ 0x5bd1_e995, cx, cy)) - fy;
+            let d = (px * px + py * py).sqrt();
+            if d < f1 {
+                f2 = f1;
+                f1 = d;
+            } else if d < f2 {
+                f2 = d;
+            }
+        }
+    }
+    (f1, f2)
+}
+
+pub fn noise_prototype() -> NodePrototype {
+    prototype(NOISE, "Noise", noise_state(), 130f32)
+}
+
+pub fn fbm_prototype() -> NodePrototype {
+    let mut state = noise_state();
+    state.extend([
+        ("octaves".to_string(), StateValue::Float(5f32)),
+        ("lacunarity".to_string(), StateValue::Float(2f32)),
+        ("gain".to_string(), StateValue::Float(0.5f32)),
+    ]);
+    prototype(FBM, "Fractal Noise", state, 200f32)
+}
+
+fn noise_state() -> HashMap<String, StateValue> {
+    HashMap::from([
+        (
+            "kind".to_string(),
+            StateValue::String(NoiseKind::Gradient.name().to_string()),
+        ),
+        ("seed".to_string(), StateValue::Float(0f32)),
+        ("scale".to_string(), StateValue::Float(8f32)),
+    ])
+}
+
+// Unconnected `x` and `y` inputs read the pixel coordinates.
+fn prototype(
+    key: &str,
+    name: &str,
+    state: HashMap<String, StateValue>,
+    height: f32,
+) -> NodePrototype {
+    let input = |name: &str, y: f32| PortPrototype {
+        local_position: vec2(0f32, y),
+        name: name.to_string(),
+        kind: PortKindPrototype::Input,
+        ty: ValueType::Float,
+    };
+    NodePrototype {
+        key: key.to_string(),
+        name: name.to_string(),
+        ports: vec![
+            input("x", 50f32),
+            input("y", 65f32),
+            PortPrototype {
+                local_position: vec2(170f32, 30f32),
+                name: "".to_string(),
+                kind: PortKindPrototype::Output(eval_noise, compile_noise),
+                ty: ValueType::Float,
+            },
+        ],
+        state_prototype: NodeState {
+            state,
+            render: Some(render_noise),
+        },
+        size: vec2(170f32, height),
+        subgraph: None,
+    }
+}
+
+fn render_noise(ui: &mut egui::Ui, state: &mut HashMap<String, StateValue>, _: Pos2) -> bool {
+    let mut changed = false;
+    let kind = get_state_string_mut("kind", state).unwrap();
+    changed |= egui::containers::ComboBox::from_id_salt("noise_kind")
+        .selected_text(kind.as_str())
+        .show_ui(ui, |ui| {
+            for k in NoiseKind::ALL {
+                ui.selectable_value(kind, k.name().to_string(), k.name());
+            }
+        })
+        .response
+        .changed();
+
+    let mut field = |name: &str, range: std::ops::RangeInclusive<f32>, speed: f32| {
+        // Fractal settings only exist on Fractal Noise nodes.
+        let Some(val) = get_state_f32_mut(name, state) else {
+            return;
+        };
+        ui.horizontal(|ui| {
+            ui.label(name);
+            changed |= ui
+                .add(egui::DragValue::new(val).range(range).speed(speed))
+                .changed();
+        });
+    };
+    field("seed", 0f32..=MAX_SEED, 1f32);
+    field("scale", 0f32..=f32::MAX, 0.1f32);
+    field("octaves", 1f32..=MAX_OCTAVES as f32, 0.05f32);
+    field("lacunarity", 0f32..=8f32, 0.01f32);
+    field("gain", 0f32..=1f32, 0.01f32);
+    changed
+}
+
+// Everything but the coordinates, in the order `sample` expects them after `x` and `y`:
+// seed, scale, octaves, lacunarity and gain. Plain Noise nodes have a single octave.
+fn settings(state: &HashMap<String, StateValue>) -> Result<(NoiseKind, [f32; 5]), EvalError> {
+    let kind = required(
+        "kind",
+        get_state_string("kind", state).and_then(|k| NoiseKind::from_name(k)),
+    )?;
+    let setting = |name, default| get_state_f32(name, state).unwrap_or(default);
+    Ok((
+        kind,
+        [
+            required("seed", get_state_f32("seed", state))?,
+            required("scale", get_state_f32("scale", state))?,
+            setting("octaves", 1f32),
+            setting("lacunarity", 2f32),
+            setting("gain", 0.5f32),
+        ],
+    ))
+}
+
+fn sample(kind: NoiseKind, args: &[f32]) -> f32 {
+    let &[x, y, seed, scale, octaves, lacunarity, gain] = args else {
+        unreachable!()
+    };
+    fbm(
+        kind,
+        x * scale,
+        y * scale,
+        seed.clamp(0f32, MAX_SEED) as u32,
+        octaves.round() as usize,
+        lacunarity,
+        gain,
+    )
+}
+
+// `sample` for one kind, as a plain function for `Compiler::call`.
+fn sampler(kind: NoiseKind) -> fn(&[f32]) -> f32 {
+    match kind {
+        NoiseKind::Value => |a| sample(NoiseKind::Value, a),
+        NoiseKind::Gradient => |a| sample(NoiseKind::Gradient, a),
+        NoiseKind::Simplex => |a| sample(NoiseKind::Simplex, a),
+        NoiseKind::WorleyF1 => |a| sample(NoiseKind::WorleyF1, a),
+        NoiseKind::WorleyF2 => |a| sample(NoiseKind::WorleyF2, a),
+        NoiseKind::WorleyF2MinusF1 => |a| sample(NoiseKind::WorleyF2MinusF1, a),
+    }
+}
+
+fn eval_noise(
+    world: &NodeWorld,
+    inputs: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+    ctx: EvalContext,
+) -> Result<Value, EvalError> {
+    let (kind, [seed, scale, octaves, lacunarity, gain]) = settings(state)?;
+    let x = get_input_f32("x", world, inputs, &ctx)?;
+    let y = get_input_f32("y", world, inputs, &ctx)?;
+    let args = [x, y, seed, scale, octaves, lacunarity, gain];
+    Ok(Value::Float(sample(kind, &args)))
+}
+
+fn compile_noise(
+    compiler: &mut Compiler,
+    inputs: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+) -> Option<Register> {
+    let (kind, settings) = settings(state).ok()?;
+    let mut args = vec![
+        compiler.input_as("x", ValueType::Float, inputs)?,
+        compiler.input_as("y", ValueType::Float, inputs)?,
+    ];
+    args.extend(settings.map(|s| compiler.constant(Value::Float(s))));
+    compiler.call(sampler(kind), &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Off the lattice, where gradient noise is always 0.
+    fn points() -> impl Iterator<Item = (f32, f32)> {
+        (0..4000).map(|i| ((i % 80) as f32 * 0.137 - 5.3, (i / 80) as f32 * 0.149 - 3.7))
+    }
+
+    #[test]
+    fn same_seed_gives_same_noise() {
+        for kind in NoiseKind::ALL {
+            for (x, y) in points() {
+                assert_eq!(
+                    kind.sample(x, y, 7),
+                    kind.sample(x, y, 7),
+                    "{}",
+                    kind.name()
+                );
+            }
+            assert_eq!(
+                fbm(kind, 1.3, 2.9, 7, 5, 2.0, 0.5),
+                fbm(kind, 1.3, 2.9, 7, 5, 2.0, 0.5)
+            );
+        }
+    }
+
+    #[test]
+    fn seeds_change_the_noise() {
+        for kind in NoiseKind::ALL {
+            for (a, b) in [(0, 1), (1, 2), (7, MAX_SEED as u32)] {
+                let differing = points()
+                    .filter(|&(x, y)| kind.sample(x, y, a) != kind.sample(x, y, b))
+                    .count();
+                assert!(differing > 3900, "{} {a} {b}: {differing}", kind.name());
+            }
+        }
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        for kind in NoiseKind::ALL {
+            let (min, max) = match kind {
+                NoiseKind::Value | NoiseKind::Gradient | NoiseKind::Simplex => (-1f32, 1f32),
+                // Some point lies within the cell itself, and within its side neighbours.
+                NoiseKind::WorleyF1 => (0f32, SQRT_2),
+                NoiseKind::WorleyF2 | NoiseKind::WorleyF2MinusF1 => (0f32, 5f32.sqrt()),
+            };
+            for seed in [0, 1, 12345, u32::MAX] {
+                for (x, y) in points() {
+                    let v = kind.sample(x, y, seed);
+                    assert!((min..=max).contains(&v), "{} at {x}, {y}: {v}", kind.name());
+                    let v = fbm(kind, x, y, seed, 6, 2.0, 0.5);
+                    assert!(
+                        (min..=max).contains(&v),
+                        "{} fBm at {x}, {y}: {v}",
+                        kind.name()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn one_octave_is_the_base_noise() {
+        for kind in NoiseKind::ALL {
+            for (x, y) in points() {
+                let base = kind.sample(x, y, 3);
+                assert_eq!(fbm(kind, x, y, 3, 1, 2.0, 0.5), base, "{}", kind.name());
+                // Fewer octaves than one are clamped to one.
+                assert_eq!(fbm(kind, x, y, 3, 0, 3.0, 0.7), base, "{}", kind.name());
+            }
+        }
+    }
+}
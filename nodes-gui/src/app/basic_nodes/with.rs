@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use egui::{Pos2, vec2};
+
+use crate::app::{
+    basic_nodes::node_tools::{get_input, get_state_string, get_state_string_mut, sync_inputs},
+    editor_graph::{
+        self, EvalContext, EvalError, NodePrototype, NodeState, NodeWorld, PortKindPrototype,
+        PortPrototype, StateValue,
+    },
+    interpreter::{Compiler, Register},
+    storage::ID,
+    value::{Value, ValueType},
+};
+
+pub const WITH: &str = "with";
+
+// The input evaluated under the modified attributes.
+const VALUE: &str = "Value";
+const WIDTH: f32 = 180f32;
+
+// Evaluates whatever feeds `Value` with the attributes listed in its state set to the inputs
+// of the same names, e.g. `x` to a warped coordinate. Unconnected ones keep their value.
+pub fn with_prototype() -> NodePrototype {
+    NodePrototype {
+        key: WITH.to_string(),
+        name: "With".to_string(),
+        ports: vec![
+            PortPrototype {
+                local_position: vec2(0f32, 50f32),
+                name: VALUE.to_string(),
+                kind: PortKindPrototype::Input,
+                ty: ValueType::Float,
+            },
+            PortPrototype {
+                local_position: vec2(WIDTH, 30f32),
+                name: "".to_string(),
+                kind: PortKindPrototype::Output(eval_with, compile_with),
+                ty: ValueType::Float,
+            },
+        ],
+        state_prototype: NodeState {
+            state: HashMap::from([
+                ("attributes".to_string(), StateValue::String("".to_string())),
+                (
+                    "type".to_string(),
+                    StateValue::String(ValueType::Float.name().to_string()),
+                ),
+            ]),
+            render: Some(render_with),
+        },
+        size: size(1),
+        subgraph: None,
+    }
+}
+
+fn size(inputs: usize) -> egui::Vec2 {
+    vec2(WIDTH, 120f32.max(70f32 + 15f32 * inputs as f32))
+}
+
+fn render_with(ui: &mut egui::Ui, state: &mut HashMap<String, StateValue>, _: Pos2) -> bool {
+    let mut changed = ui
+        .add(
+            egui::TextEdit::singleline(get_state_string_mut("attributes", state).unwrap())
+                .hint_text("x, y"),
+        )
+        .changed();
+
+    let ty = get_state_string_mut("type", state).unwrap();
+    egui::ComboBox::from_id_salt("type")
+        .selected_text(ty.as_str())
+        .show_ui(ui, |ui| {
+            for option in ValueType::ALL {
+                changed |= ui
+                    .selectable_value(ty, option.name().to_string(), option.name())
+                    .changed();
+            }
+        });
+    changed
+}
+
+// The comma separated attribute names, without blanks, repeats or the name of the value input.
+fn attributes(state: &HashMap<String, StateValue>) -> Vec<&str> {
+    let mut names = Vec::new();
+    for name in get_state_string("attributes", state)
+        .map(|s| s.split(','))
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+    {
+        if !name.is_empty() && name != VALUE && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+fn value_type(state: &HashMap<String, StateValue>) -> ValueType {
+    get_state_string("type", state)
+        .and_then(|ty| ValueType::from_name(ty))
+        .unwrap_or(ValueType::Float)
+}
+
+// Gives a With node one float input per attribute it overrides and retypes its value.
+pub fn sync_ports(world: &mut NodeWorld, node: ID) {
+    let n = world.nodes.get(node);
+    if n.key != WITH {
+        return;
+    }
+    let state = &n.state.state;
+    let ty = value_type(state);
+    let names: Vec<String> = attributes(state).into_iter().map(String::from).collect();
+    let mut inputs = vec![(VALUE, ty)];
+    inputs.extend(names.iter().map(|name| (name.as_str(), ValueType::Float)));
+    sync_inputs(world, node, &inputs, size(inputs.len()));
+
+    let output = *world.nodes.get(node).ports.last().unwrap();
+    if world.port_info(output).ty != ty {
+        world.set_port_type(output, ty);
+    }
+}
+
+// Only connected inputs override, anything else would set an attribute to itself.
+fn connected<'a>(
+    state: &'a HashMap<String, StateValue>,
+    inputs: &HashMap<String, Option<ID>>,
+) -> Vec<&'a str> {
+    attributes(state)
+        .into_iter()
+        .filter(|name| matches!(inputs.get(*name), Some(Some(_))))
+        .collect()
+}
+
+fn eval_with(
+    world: &NodeWorld,
+    inputs: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+    ctx: EvalContext,
+) -> Result<Value, EvalError> {
+    let mut inner = ctx.clone();
+    for name in connected(state, inputs) {
+        let val = editor_graph::convert(get_input(name, world, inputs, &ctx)?, ValueType::Float)?;
+        inner.insert_mut(name.to_string(), val);
+    }
+    get_input(VALUE, world, inputs, &inner)
+}
+
+fn compile_with(
+    compiler: &mut Compiler,
+    inputs: &HashMap<String, Option<ID>>,
+    state: &HashMap<String, StateValue>,
+) -> Option<Register> {
+    let mut bindings = HashMap::new();
+    for name in connected(state, inputs) {
+        let reg = compiler.input_as(name, ValueType::Float, inputs)?;
+        bindings.insert(name.to_string(), reg);
+    }
+    compiler.with_attributes(bindings, |compiler| compiler.input(VALUE, inputs))
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
+
+use crate::app::{
+    editor_graph::{NodeWorld, PortKind},
+    interpreter,
+    render::VARYING_ATTRIBUTES,
+    storage::ID,
+};
+
+// The context attributes each output port of a graph reads, directly or through anything
+// upstream of it. Ports that don't compile, e.g. because of mismatched types, aren't included.
+#[derive(Default)]
+pub struct Dependencies {
+    ports: HashMap<ID, BTreeSet<String>>,
+    // Outputs linked to more than one input. Others are only evaluated again along with what
+    // reads them, so memoizing them rarely pays off.
+    shared: HashSet<ID>,
+    // Those of the graphs of group nodes, by node.
+    groups: HashMap<ID, Arc<Dependencies>>,
+}
+
+impl Dependencies {
+    pub fn analyze(world: &NodeWorld) -> Self {
+        let mut ports = HashMap::new();
+        for (id, port) in world.ports.with_ids() {
+            if port.connection_kind.is_input() || ports.contains_key(id) {
+                continue;
+            }
+            // Compiling a port also compiles, and so finds out about, everything upstream.
+            let Some((program, registers)) =
+                interpreter::compile_with_precomputed(world, *id, HashMap::new())
+            else {
+                continue;
+            };
+            let slots = program.register_attributes();
+            for (port, reg) in registers {
+                let names = slots[reg.index()]
+                    .iter()
+                    .map(|slot| program.attributes()[*slot].clone())
+                    .collect();
+                ports.insert(port, names);
+            }
+        }
+        let mut readers = HashMap::new();
+        for port in world.ports.iter() {
+            if let PortKind::Input(Some(out)) = port.connection_kind {
+                *readers.entry(out).or_insert(0) += 1;
+            }
+        }
+        let shared = readers
+            .into_iter()
+            .filter(|(_, n)| *n > 1)
+            .map(|(out, _)| out)
+            .collect();
+        let groups = world
+            .nodes
+            .with_ids()
+            .into_iter()
+            .filter_map(|(id, n)| Some((*id, Arc::new(Self::analyze(world.subgraph(n)?)))))
+            .collect();
+        Dependencies {
+            ports,
+            shared,
+            groups,
+        }
+    }
+
+    pub fn get(&self, port: ID) -> Option<&BTreeSet<String>> {
+        self.ports.get(&port)
+    }
+
+    pub fn is_shared(&self, port: ID) -> bool {
+        self.shared.contains(&port)
+    }
+
+    pub fn group(&self, node: ID) -> Option<&Arc<Dependencies>> {
+        self.groups.get(&node)
+    }
+
+    // Whether the port has the same value at every pixel of an image.
+    pub fn is_uniform(&self, port: ID) -> Option<bool> {
+        let deps = self.get(port)?;
+        Some(!VARYING_ATTRIBUTES.iter().any(|a| deps.contains(*a)))
+    }
+}
@@ -1,49 +1,75 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
 
 use egui::Pos2;
+use serde::{Deserialize, Serialize};
 
-use crate::app::storage::{ID, Storage};
+use crate::app::{
+    dependencies::Dependencies,
+    expr::ParseError,
+    group,
+    interpreter::{Compiler, Register},
+    registry::PrototypeRegistry,
+    storage::{ID, Storage},
+    value::{Value, ValueType},
+};
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum StateValue {
     Float(f32),
     Char(char),
     String(String),
+    Color([f32; 4]),
 }
 
-type OutputEvaluationFn = fn(
+// Attribute values visible to evaluation, e.g. the pixel coordinates `x` and `y`.
+pub type EvalContext = rpds::HashTrieMap<String, Value>;
+
+pub type OutputEvaluationFn = fn(
     &NodeWorld,
     &HashMap<String, Option<ID>>,
     &HashMap<String, StateValue>,
-    rpds::HashTrieMap<String, f32>,
-) -> Option<f32>;
+    EvalContext,
+) -> Result<Value, EvalError>;
+
+// Emits the instructions computing an output port, see `interpreter::Compiler`.
+pub type OutputCompileFn = fn(
+    &mut Compiler,
+    &HashMap<String, Option<ID>>,
+    &HashMap<String, StateValue>,
+) -> Option<Register>;
 
 #[derive(Clone)]
 pub enum PortKindPrototype {
     Input,
-    Output(OutputEvaluationFn),
+    Output(OutputEvaluationFn, OutputCompileFn),
 }
 
 impl PortKindPrototype {
     pub fn instantiate(&self) -> PortKind {
         match self {
             PortKindPrototype::Input => PortKind::Input(None),
-            PortKindPrototype::Output(f) => PortKind::Output(*f),
+            PortKindPrototype::Output(..) => PortKind::Output,
         }
     }
 }
 
-#[derive(Clone)]
+// How evaluation and compilation handle an output is part of its `PortPrototype`.
+#[derive(Clone, Copy)]
 pub enum PortKind {
     Input(Option<ID>),
-    Output(OutputEvaluationFn),
+    Output,
 }
 
 impl PortKind {
     pub fn is_input(&self) -> bool {
         match self {
             PortKind::Input(_) => true,
-            PortKind::Output(_) => false,
+            PortKind::Output => false,
         }
     }
 
@@ -52,9 +78,10 @@ impl PortKind {
     }
 }
 
+// Name, type and position of a port are those at the same index of its node's port list, see
+// `NodeWorld::port_info`.
 #[derive(Clone)]
 pub struct Port {
-    pub port_info: PortPrototype,
     pub node: ID,
     pub connection_kind: PortKind,
 }
@@ -64,103 +91,896 @@ pub struct PortPrototype {
     pub local_position: egui::Vec2,
     pub name: String,
     pub kind: PortKindPrototype,
+    // What an output produces, or what an input reads its value as.
+    pub ty: ValueType,
 }
 
+type NodeRenderFn = fn(&mut egui::Ui, &mut HashMap<String, StateValue>, egui::Pos2) -> bool;
+
 #[derive(Clone, Default)]
 pub struct NodeState {
     pub state: HashMap<String, StateValue>,
-    pub render: Option<fn(&mut egui::Ui, &mut HashMap<String, StateValue>, egui::Pos2) -> bool>,
+    pub render: Option<NodeRenderFn>,
 }
 
 // Contains all rendering information for a kind of node.
 #[derive(Clone)]
 pub struct NodePrototype {
+    // Stable identifier the prototype is registered and saved under, see `registry`.
+    pub key: String,
     pub name: String,
     pub ports: Vec<PortPrototype>,
     pub state_prototype: NodeState,
     pub size: egui::Vec2,
+    // Only set for group nodes, whose outputs are evaluated from this graph rather than through
+    // their port functions, see `group`.
+    pub subgraph: Option<Arc<NodeWorld>>,
 }
 
+// Ports and size of a node whose ports follow its state or subgraph rather than its prototype.
+#[derive(Clone)]
+pub struct Layout {
+    pub ports: Vec<PortPrototype>,
+    pub size: egui::Vec2,
+}
+
+#[derive(Clone)]
 pub struct Node {
     pub ports: Vec<ID>,
 
-    // In future, should be Rc or id into list of existing prototypes
-    pub prototype: NodePrototype,
+    // Registry key of the node's prototype, which is looked up through the world it is in so
+    // re-registering a prototype applies to every node of its kind.
+    pub key: String,
     pub state: NodeState,
 
     pub pos: egui::Pos2,
+
+    // Replaces the prototype's ports and size, see `NodeWorld::set_layout`.
+    pub layout: Option<Layout>,
+    // The graph of a group made or edited in the editor, in place of its prototype's.
+    pub subgraph: Option<Arc<NodeWorld>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationError {
+    // The nodes along the loop, in the direction data flows.
+    Cycle(Vec<ID>),
+    // An input still pointing at a port that has since been removed.
+    DanglingLink { input: ID },
+}
+
+// Why a link between two ports is not allowed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectError {
+    NotOutputToInput,
+    SameNode,
+    IncompatibleTypes { from: ValueType, to: ValueType },
+    Cycle,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::NotOutputToInput => write!(f, "Links go from an output to an input"),
+            ConnectError::SameNode => write!(f, "Can't link a node to itself"),
+            ConnectError::IncompatibleTypes { from, to } => {
+                write!(f, "Can't convert {} to {}", from.name(), to.name())
+            }
+            ConnectError::Cycle => write!(f, "Would create a cycle"),
+        }
+    }
+}
+
+// Why evaluating a port failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalErrorKind {
+    // Read by an unconnected input or an Attribute node, but not set in the context.
+    MissingAttribute(String),
+    WrongType { from: ValueType, to: ValueType },
+    // A setting of the node's state that is missing or has the wrong type.
+    InvalidState(String),
+    UnknownOp(String),
+    InvalidFormula(ParseError),
+    // The Group Output of this name doesn't exist or isn't connected inside the group.
+    MissingGroupOutput(String),
+    // Group inputs only have values while the group's graph is evaluated for a group node.
+    OutsideGroup(String),
+    MissingPort,
+    NotAnOutput,
+    // A compiled program failed where the tree-walker didn't, which is a bug in one of them.
+    CompiledOnly,
+}
+
+impl fmt::Display for EvalErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalErrorKind::MissingAttribute(name) => write!(f, "Attribute \"{name}\" isn't set"),
+            EvalErrorKind::WrongType { from, to } => {
+                write!(f, "Can't convert {} to {}", from.name(), to.name())
+            }
+            EvalErrorKind::InvalidState(name) => write!(f, "Setting \"{name}\" is invalid"),
+            EvalErrorKind::UnknownOp(op) => write!(f, "Unknown operation \"{op}\""),
+            EvalErrorKind::InvalidFormula(e) => write!(f, "Invalid formula {e}"),
+            EvalErrorKind::MissingGroupOutput(name) => {
+                write!(f, "Group output \"{name}\" isn't connected")
+            }
+            EvalErrorKind::OutsideGroup(name) => {
+                write!(f, "Group input \"{name}\" has no value outside a group")
+            }
+            EvalErrorKind::MissingPort => write!(f, "Port doesn't exist"),
+            EvalErrorKind::NotAnOutput => write!(f, "Only outputs can be evaluated"),
+            EvalErrorKind::CompiledOnly => {
+                write!(f, "Compiled program failed where the tree-walker didn't")
+            }
+        }
+    }
+}
+
+// A failure evaluating a port. Nodes downstream pass it on unchanged, so it points at where
+// it started rather than at the port that was asked for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalError {
+    // Filled in by `NodeWorld::evaluate_output_port` as the error leaves the port it happened
+    // at; evaluation functions leave them empty.
+    pub node: Option<ID>,
+    pub port: Option<ID>,
+    pub kind: EvalErrorKind,
+}
+
+impl EvalError {
+    pub fn new(kind: EvalErrorKind) -> Self {
+        EvalError {
+            node: None,
+            port: None,
+            kind,
+        }
+    }
+
+    pub fn invalid_state(name: &str) -> Self {
+        EvalError::new(EvalErrorKind::InvalidState(name.to_string()))
+    }
+
+    // Keeps the origin of an error that already has one.
+    pub fn at(self, node: ID, port: ID) -> Self {
+        EvalError {
+            node: self.node.or(Some(node)),
+            port: self.port.or(Some(port)),
+            kind: self.kind,
+        }
+    }
+}
+
+impl From<EvalErrorKind> for EvalError {
+    fn from(kind: EvalErrorKind) -> Self {
+        EvalError::new(kind)
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+// `Value::convert`, failing with the types involved.
+pub fn convert(val: Value, to: ValueType) -> Result<Value, EvalError> {
+    val.convert(to).ok_or_else(|| {
+        EvalError::new(EvalErrorKind::WrongType {
+            from: val.value_type(),
+            to,
+        })
+    })
 }
 
+#[derive(Clone, Default)]
 pub struct NodeWorld {
     pub nodes: Storage<Node>,
     pub ports: Storage<Port>,
+    // What the keys of nodes refer to. Empty by default, which only suits worlds without nodes.
+    registry: Arc<PrototypeRegistry>,
 }
 
-impl Default for NodeWorld {
-    fn default() -> Self {
-        Self {
-            nodes: Storage::default(),
-            ports: Storage::default(),
+impl NodeWorld {
+    pub fn new(registry: Arc<PrototypeRegistry>) -> Self {
+        NodeWorld {
+            registry,
+            ..Default::default()
         }
     }
-}
 
-impl NodeWorld {
-    pub fn get_port_pos(&self, id: ID) -> Pos2 {
-        self.get_port_pos_from_ref(self.ports.get(id))
+    pub fn registry(&self) -> &Arc<PrototypeRegistry> {
+        &self.registry
+    }
+
+    // Switches to another registry, e.g. after a prototype was re-registered, bringing the
+    // ports of every node in line with its current definition. Subgraphs of groups switch too.
+    pub fn set_registry(&mut self, registry: Arc<PrototypeRegistry>) {
+        if Arc::ptr_eq(&self.registry, &registry) {
+            return;
+        }
+        let old: Vec<(ID, Vec<(String, bool)>)> = self
+            .nodes
+            .ids()
+            .map(|id| (*id, self.port_names(*id)))
+            .collect();
+        self.registry = registry;
+        // Links are only checked once every node has its new ports.
+        for (id, names) in &old {
+            if let Some(inner) = &mut self.nodes.get_mut(*id).subgraph
+                && !Arc::ptr_eq(&inner.registry, &self.registry)
+            {
+                Arc::make_mut(inner).set_registry(self.registry.clone());
+            }
+            self.rebuild_ports(*id, names);
+        }
+        for (id, _) in old {
+            self.drop_incompatible_links(id);
+        }
     }
 
-    pub fn get_port_pos_from_ref(&self, port: &Port) -> Pos2 {
-        self.nodes.get(port.node).pos + port.port_info.local_position
+    // Panics if the node's key isn't registered, like `Storage::get` for unknown IDs.
+    pub fn prototype(&self, node: &Node) -> &NodePrototype {
+        self.registry.get(&node.key).expect("unknown prototype key")
     }
 
-    fn create_port_and_link(&mut self, node_id: ID, port_proto: &PortPrototype) -> ID {
-        let new_port = self.ports.create(Port {
-            port_info: port_proto.clone(),
-            node: node_id,
-            connection_kind: port_proto.kind.instantiate(),
-        });
-        new_port.1
+    pub fn node_size(&self, node: &Node) -> egui::Vec2 {
+        match &node.layout {
+            Some(layout) => layout.size,
+            None => self.prototype(node).size,
+        }
     }
 
-    pub fn create_node(&mut self, pos: Pos2, prototype: &NodePrototype) {
+    // Set for group nodes, whose outputs are evaluated from it, see `group`.
+    pub fn subgraph<'a>(&'a self, node: &'a Node) -> Option<&'a Arc<NodeWorld>> {
+        node.subgraph
+            .as_ref()
+            .or(self.prototype(node).subgraph.as_ref())
+    }
+
+    fn port_layout<'a>(&'a self, node: &'a Node) -> &'a [PortPrototype] {
+        match &node.layout {
+            Some(layout) => &layout.ports,
+            None => &self.prototype(node).ports,
+        }
+    }
+
+    pub fn port_info(&self, id: ID) -> &PortPrototype {
+        let node = self.nodes.get(self.ports.get(id).node);
+        let index = node.ports.iter().position(|p| *p == id).unwrap();
+        &self.port_layout(node)[index]
+    }
+
+    pub fn get_port_pos(&self, id: ID) -> Pos2 {
+        self.nodes.get(self.ports.get(id).node).pos + self.port_info(id).local_position
+    }
+
+    // Panics if `key` isn't registered.
+    pub fn create_node(&mut self, pos: Pos2, key: &str) -> ID {
+        let prototype = self
+            .registry
+            .get(key)
+            .expect("unknown prototype key")
+            .clone();
         let new_obj = self
             .nodes
             .create(Node {
                 ports: Vec::new(),
-                prototype: prototype.clone(),
+                key: key.to_string(),
                 state: prototype.state_prototype.clone(),
                 pos,
+                layout: None,
+                subgraph: None,
             })
             .1;
 
         for p in &prototype.ports {
-            let new_p = self.create_port_and_link(new_obj, p);
+            let new_p = self
+                .ports
+                .create(Port {
+                    node: new_obj,
+                    connection_kind: p.kind.instantiate(),
+                })
+                .1;
             self.nodes.get_mut(new_obj).ports.push(new_p);
         }
+
+        new_obj
+    }
+
+    // Removes a node along with its ports, leaving every input that read from it unconnected.
+    pub fn remove_node(&mut self, id: ID) {
+        let node_ports = self.nodes.get(id).ports.clone();
+        self.disconnect_inputs(|out| node_ports.contains(&out));
+
+        for p in node_ports {
+            self.ports.remove(p);
+        }
+        self.nodes.remove(id);
     }
 
-    pub fn evaluate_output_port(&self, id: ID, ctx: rpds::HashTrieMap<String, f32>) -> Option<f32> {
-        let port = self.ports.get(id);
+    // Gives a node its own ports and size, or with `None` goes back to its prototype's.
+    pub fn set_layout(&mut self, id: ID, layout: Option<Layout>) {
+        let old = self.port_names(id);
+        self.nodes.get_mut(id).layout = layout;
+        self.rebuild_ports(id, &old);
+        self.drop_incompatible_links(id);
+    }
 
-        let eval = match &port.connection_kind {
-            PortKind::Input(_) => {
-                return None;
+    // Name and direction of each port of a node, in order.
+    fn port_names(&self, id: ID) -> Vec<(String, bool)> {
+        self.port_layout(self.nodes.get(id))
+            .iter()
+            .map(|p| (p.name.clone(), matches!(p.kind, PortKindPrototype::Input)))
+            .collect()
+    }
+
+    // Makes the ports of a node match its port list after it changed from `old_names`. Ports
+    // that still exist with the same name and direction keep their IDs and links, as long as
+    // their new type can still carry them, see `drop_incompatible_links`.
+    fn rebuild_ports(&mut self, id: ID, old_names: &[(String, bool)]) {
+        let layout = self.port_names(id);
+        let old_ports = std::mem::take(&mut self.nodes.get_mut(id).ports);
+        let mut ports = Vec::new();
+        for (name, is_input) in &layout {
+            let existing = old_ports
+                .iter()
+                .zip(old_names)
+                .find(|(p, old)| old.0 == *name && old.1 == *is_input && !ports.contains(*p))
+                .map(|(p, _)| *p);
+            match existing {
+                Some(p) => ports.push(p),
+                None => {
+                    let connection_kind = if *is_input {
+                        PortKind::Input(None)
+                    } else {
+                        PortKind::Output
+                    };
+                    ports.push(
+                        self.ports
+                            .create(Port {
+                                node: id,
+                                connection_kind,
+                            })
+                            .1,
+                    );
+                }
+            }
+        }
+        for p in old_ports {
+            if !ports.contains(&p) {
+                self.remove_port(p);
             }
-            PortKind::Output(eval) => eval,
+        }
+
+        self.nodes.get_mut(id).ports = ports;
+    }
+
+    pub fn set_port_type(&mut self, id: ID, ty: ValueType) {
+        let node = self.ports.get(id).node;
+        let n = self.nodes.get(node);
+        let index = n.ports.iter().position(|p| *p == id).unwrap();
+        let mut layout = Layout {
+            ports: self.port_layout(n).to_vec(),
+            size: self.node_size(n),
         };
+        layout.ports[index].ty = ty;
+        self.nodes.get_mut(node).layout = Some(layout);
+        self.drop_incompatible_links(node);
+    }
 
+    // Disconnects the links into and out of `node` whose types no longer convert.
+    fn drop_incompatible_links(&mut self, node: ID) {
+        let node_ports = &self.nodes.get(node).ports;
+        let mut broken = Vec::new();
+        for (id, p) in self.ports.with_ids() {
+            if let PortKind::Input(Some(out)) = p.connection_kind
+                && (node_ports.contains(id) || node_ports.contains(&out))
+                && self.ports.exists(out)
+                && !self.port_info(out).ty.converts_to(self.port_info(*id).ty)
+            {
+                broken.push(*id);
+            }
+        }
+        for inp in broken {
+            self.disconnect(inp);
+        }
+    }
+
+    fn remove_port(&mut self, id: ID) {
+        self.disconnect_inputs(|out| out == id);
+        self.ports.remove(id);
+    }
+
+    // Only the inputs that change are touched, the rest stay shared with undo snapshots.
+    fn disconnect_inputs(&mut self, from: impl Fn(ID) -> bool) {
+        let linked: Vec<ID> = self
+            .ports
+            .with_ids()
+            .into_iter()
+            .filter(|(_, p)| matches!(p.connection_kind, PortKind::Input(Some(out)) if from(out)))
+            .map(|(id, _)| *id)
+            .collect();
+        for p in linked {
+            self.ports.get_mut(p).connection_kind = PortKind::Input(None);
+        }
+    }
+
+    pub fn disconnect(&mut self, inp: ID) {
+        let port = self.ports.get_mut(inp);
+        if port.connection_kind.is_input() {
+            port.connection_kind = PortKind::Input(None);
+        }
+    }
+
+    // Maps each input port name of a node to the output port it is connected to.
+    pub fn direct_inputs(&self, node: ID) -> HashMap<String, Option<ID>> {
         let mut direct_inputs_map = HashMap::new();
+        for p in &self.nodes.get(node).ports {
+            if let PortKind::Input(i) = &self.ports.get(*p).connection_kind {
+                direct_inputs_map.insert(self.port_info(*p).name.clone(), *i);
+            }
+        }
+        direct_inputs_map
+    }
+
+    // Nodes with an output connected to one of `node`'s inputs.
+    pub fn upstream_nodes(&self, node: ID) -> Vec<ID> {
+        self.nodes
+            .get(node)
+            .ports
+            .iter()
+            .filter_map(|p| match self.ports.get(*p).connection_kind {
+                PortKind::Input(Some(out)) => self.ports.try_get(out).map(|o| o.node),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Whether `node` is `of` itself or feeds into it through any chain of links.
+    pub fn is_upstream_of(&self, node: ID, of: ID) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![of];
+        while let Some(n) = stack.pop() {
+            if n == node {
+                return true;
+            }
+            if visited.insert(n) {
+                stack.extend(self.upstream_nodes(n));
+            }
+        }
+        false
+    }
+
+    // Linking `out` into `inp` closes a loop if `inp`'s node already feeds `out`'s node.
+    pub fn would_create_cycle(&self, out: ID, inp: ID) -> bool {
+        self.is_upstream_of(self.ports.get(inp).node, self.ports.get(out).node)
+    }
+
+    pub fn can_connect(&self, out: ID, inp: ID) -> Result<(), ConnectError> {
+        let out_port = self.ports.get(out);
+        let inp_port = self.ports.get(inp);
+
+        if !out_port.connection_kind.is_output() || !inp_port.connection_kind.is_input() {
+            return Err(ConnectError::NotOutputToInput);
+        }
+        if out_port.node == inp_port.node {
+            return Err(ConnectError::SameNode);
+        }
+        let (from, to) = (self.port_info(out).ty, self.port_info(inp).ty);
+        if !from.converts_to(to) {
+            return Err(ConnectError::IncompatibleTypes { from, to });
+        }
+        if self.would_create_cycle(out, inp) {
+            return Err(ConnectError::Cycle);
+        }
+        Ok(())
+    }
+
+    pub fn connect(&mut self, out: ID, inp: ID) -> Result<(), ConnectError> {
+        self.can_connect(out, inp)?;
+        self.ports.get_mut(inp).connection_kind = PortKind::Input(Some(out));
+        Ok(())
+    }
+
+    // Reports structural problems that would make evaluation misbehave, e.g. in loaded graphs.
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+
+        for (id, p) in self.ports.with_ids() {
+            if let PortKind::Input(Some(out)) = p.connection_kind
+                && !self.ports.exists(out)
+            {
+                errors.push(ValidationError::DanglingLink { input: *id });
+            }
+        }
+
+        let mut finished = HashSet::new();
+        let mut path = Vec::new();
+        for id in self.nodes.ids() {
+            self.find_cycles_from(*id, &mut path, &mut finished, &mut errors);
+        }
+        errors
+    }
+
+    fn find_cycles_from(
+        &self,
+        node: ID,
+        path: &mut Vec<ID>,
+        finished: &mut HashSet<ID>,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        if finished.contains(&node) {
+            return;
+        }
+        // `path` runs from downstream to upstream, so a repeat closes a loop.
+        if let Some(start) = path.iter().position(|n| *n == node) {
+            let mut cycle = path[start..].to_vec();
+            cycle.reverse();
+            errors.push(ValidationError::Cycle(cycle));
+            return;
+        }
+
+        path.push(node);
+        for up in self.upstream_nodes(node) {
+            self.find_cycles_from(up, path, finished, errors);
+        }
+        path.pop();
+        finished.insert(node);
+    }
+
+    // Recursive tree-walking evaluation. Rendering goes through `interpreter::compile` instead;
+    // this stays as the reference implementation the compiled programs must agree with.
+    //
+    // Within one outermost call, each port is evaluated at most once per context, so nodes
+    // read by several others don't make diamond-shaped graphs exponential.
+    pub fn evaluate_output_port(&self, id: ID, ctx: EvalContext) -> Result<Value, EvalError> {
+        let _scope = MemoScope::enter(true, None);
+        self.evaluate_memoized(id, ctx)
+    }
+
+    // Like `evaluate_output_port`, but a port is only evaluated again for other values of the
+    // attributes it reads. `deps` must be of this world, analyzing it costs about as much as
+    // evaluating it, so it pays off when evaluating many contexts.
+    pub fn evaluate_output_port_with(
+        &self,
+        id: ID,
+        ctx: EvalContext,
+        deps: &Arc<Dependencies>,
+    ) -> Result<Value, EvalError> {
+        let _scope = MemoScope::enter(true, Some(deps.clone()));
+        self.evaluate_memoized(id, ctx)
+    }
+
+    // Like `evaluate_output_port`, but runs shared nodes once for every path through them.
+    // Only there to measure what the memo saves.
+    pub fn evaluate_output_port_unmemoized(
+        &self,
+        id: ID,
+        ctx: EvalContext,
+    ) -> Result<Value, EvalError> {
+        let _scope = MemoScope::enter(false, None);
+        self.evaluate_memoized(id, ctx)
+    }
+
+    // `evaluate_output_port` on the graph of the group node `group`, while evaluating that
+    // node. Shares the memo of the outer graph.
+    pub fn evaluate_group_output_port(
+        &self,
+        group: ID,
+        id: ID,
+        ctx: EvalContext,
+    ) -> Result<Value, EvalError> {
+        MEMO.with_borrow_mut(|memo| memo.as_mut().map(|memo| memo.enter_group(group)));
+        let val = self.evaluate_output_port(id, ctx);
+        MEMO.with_borrow_mut(|memo| memo.as_mut().map(Memo::leave_group));
+        val
+    }
+
+    fn evaluate_memoized(&self, id: ID, ctx: EvalContext) -> Result<Value, EvalError> {
+        let key = MEMO.with_borrow(|memo| memo.as_ref()?.key(id, &ctx));
+        let Some(key) = key else {
+            return self.evaluate_output_port_uncached(id, ctx);
+        };
+        let cached = MEMO.with_borrow(|memo| memo.as_ref()?.results.get(&key).cloned());
+        if let Some(val) = cached {
+            return val;
+        }
+
+        let val = self.evaluate_output_port_uncached(id, ctx);
+        MEMO.with_borrow_mut(|memo| {
+            if let Some(memo) = memo {
+                memo.results.insert(key, val.clone());
+            }
+        });
+        val
+    }
+
+    fn evaluate_output_port_uncached(&self, id: ID, ctx: EvalContext) -> Result<Value, EvalError> {
+        let port = self
+            .ports
+            .try_get(id)
+            .ok_or(EvalError::new(EvalErrorKind::MissingPort))?;
+
+        let info = self.port_info(id);
+        let PortKindPrototype::Output(eval, _) = info.kind else {
+            return Err(EvalError::new(EvalErrorKind::NotAnOutput).at(port.node, id));
+        };
+
         let node = self.nodes.get(port.node);
-        for p in &node.ports {
-            let node_port = self.ports.get(*p);
-            if let PortKind::Input(i) = &node_port.connection_kind {
-                direct_inputs_map.insert(node_port.port_info.name.clone(), *i);
+        let val = if self.subgraph(node).is_some() {
+            group::evaluate_output(self, port.node, &info.name, ctx)
+        } else {
+            eval(self, &self.direct_inputs(port.node), &node.state.state, ctx)
+        };
+        val.and_then(|val| convert(val, info.ty))
+            .map_err(|e| e.at(port.node, id))
+    }
+}
+
+// Results of the outermost `evaluate_output_port` call running on this thread. Keyed by the
+// path of group nodes to the graph as well as port, since group subgraphs are evaluated
+// within the same call.
+struct Memo {
+    enabled: bool,
+    // The group nodes whose graphs are being evaluated, outermost first.
+    path: Vec<ID>,
+    // What the ports of the outermost graph and of the graph at each step of `path` read,
+    // where known.
+    dependencies: Vec<Option<Arc<Dependencies>>>,
+    results: HashMap<MemoKey, Result<Value, EvalError>>,
+}
+
+type MemoKey = (Vec<ID>, ID, ContextKey);
+
+impl Memo {
+    fn enter_group(&mut self, node: ID) {
+        let deps = self.dependencies.last().cloned().flatten();
+        self.dependencies
+            .push(deps.and_then(|deps| deps.group(node).cloned()));
+        self.path.push(node);
+    }
+
+    fn leave_group(&mut self) {
+        self.dependencies.pop();
+        self.path.pop();
+    }
+
+    // `None` for ports not worth memoizing.
+    fn key(&self, port: ID, ctx: &EvalContext) -> Option<MemoKey> {
+        let deps = self.dependencies.last().and_then(Option::as_ref);
+        if !self.enabled || deps.is_some_and(|deps| !deps.is_shared(port)) {
+            return None;
+        }
+        let context = match deps.and_then(|deps| deps.get(port)) {
+            Some(reads) => ContextKey::Reads(
+                reads
+                    .iter()
+                    .map(|name| ctx.get(name).copied().map(ValueBits::from))
+                    .collect(),
+            ),
+            None => {
+                let mut all: Vec<_> = ctx
+                    .iter()
+                    .map(|(name, val)| (name.clone(), ValueBits::from(*val)))
+                    .collect();
+                all.sort_by(|a, b| a.0.cmp(&b.0));
+                ContextKey::Whole(all)
+            }
+        };
+        Some((self.path.clone(), port, context))
+    }
+}
+
+// The part of a context a port can see: the attributes it reads, in name order, and `None`
+// for those missing. Where its reads aren't known it sees all of it.
+#[derive(PartialEq, Eq, Hash)]
+enum ContextKey {
+    Reads(Vec<Option<ValueBits>>),
+    Whole(Vec<(String, ValueBits)>),
+}
+
+// A `Value` with its floats as bits, so it can be hashed. Unlike `==`, equal bits always give
+// equal results, e.g. 0 and -0 differ.
+#[derive(PartialEq, Eq, Hash)]
+struct ValueBits(ValueType, [u32; 4]);
+
+impl From<Value> for ValueBits {
+    fn from(val: Value) -> Self {
+        let mut bits = [0u32; 4];
+        let floats: &[f32] = match &val {
+            Value::Float(f) => std::slice::from_ref(f),
+            Value::Vec2(v) => v,
+            Value::Vec3(v) => v,
+            Value::Color(c) => c,
+            Value::Bool(b) => {
+                bits[0] = *b as u32;
+                &[]
+            }
+            Value::Int(i) => {
+                bits[0] = *i as u32;
+                &[]
+            }
+        };
+        for (bits, f) in bits.iter_mut().zip(floats) {
+            *bits = f.to_bits();
+        }
+        ValueBits(val.value_type(), bits)
+    }
+}
+
+thread_local! {
+    static MEMO: RefCell<Option<Memo>> = const { RefCell::new(None) };
+}
+
+// Starts a memo unless one is active, and drops it again when the call that started it ends,
+// even by panicking. A disabled memo only keeps nested calls from starting one.
+struct MemoScope {
+    outermost: bool,
+}
+
+impl MemoScope {
+    fn enter(enabled: bool, dependencies: Option<Arc<Dependencies>>) -> MemoScope {
+        let outermost = MEMO.with_borrow_mut(|memo| {
+            let outermost = memo.is_none();
+            if outermost {
+                *memo = Some(Memo {
+                    enabled,
+                    path: Vec::new(),
+                    dependencies: vec![dependencies],
+                    results: HashMap::new(),
+                });
             }
+            outermost
+        });
+        MemoScope { outermost }
+    }
+}
+
+impl Drop for MemoScope {
+    fn drop(&mut self) {
+        if self.outermost {
+            MEMO.with_borrow_mut(|memo| *memo = None);
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::basic_nodes::{
+        add::BINARY_MATH,
+        basic_registry,
+        color::{COLOR, SPLIT_COLOR},
+        constant::CONSTANT,
+    };
+
+    fn port(world: &NodeWorld, node: ID, index: usize) -> ID {
+        world.nodes.get(node).ports[index]
+    }
+
+    #[test]
+    fn links_go_from_an_output_to_an_input() {
+        let mut world = NodeWorld::new(Arc::new(basic_registry()));
+        let constant = world.create_node(Default::default(), CONSTANT);
+        let math = world.create_node(Default::default(), BINARY_MATH);
+        let (a, b, out) = (
+            port(&world, math, 0),
+            port(&world, math, 1),
+            port(&world, math, 2),
+        );
+        let value = port(&world, constant, 0);
+
+        assert_eq!(world.can_connect(value, a), Ok(()));
+        assert_eq!(
+            world.can_connect(a, value),
+            Err(ConnectError::NotOutputToInput)
+        );
+        assert_eq!(
+            world.can_connect(value, out),
+            Err(ConnectError::NotOutputToInput)
+        );
+        assert_eq!(world.can_connect(a, b), Err(ConnectError::NotOutputToInput));
+    }
 
-        let node_state = &node.state.state;
+    #[test]
+    fn nodes_cant_link_to_themselves() {
+        let mut world = NodeWorld::new(Arc::new(basic_registry()));
+        let math = world.create_node(Default::default(), BINARY_MATH);
+        let (a, out) = (port(&world, math, 0), port(&world, math, 2));
 
-        eval(self, &direct_inputs_map, node_state, ctx)
+        assert_eq!(world.can_connect(out, a), Err(ConnectError::SameNode));
+        assert_eq!(world.connect(out, a), Err(ConnectError::SameNode));
+        assert!(matches!(
+            world.ports.get(a).connection_kind,
+            PortKind::Input(None)
+        ));
+    }
+
+    #[test]
+    fn links_need_convertible_types() {
+        let mut world = NodeWorld::new(Arc::new(basic_registry()));
+        let constant = world.create_node(Default::default(), CONSTANT);
+        let color = world.create_node(Default::default(), COLOR);
+        let split = world.create_node(Default::default(), SPLIT_COLOR);
+        let math = world.create_node(Default::default(), BINARY_MATH);
+        let (float_out, color_out) = (port(&world, constant, 0), port(&world, color, 0));
+        let (color_in, float_in) = (port(&world, split, 0), port(&world, math, 0));
+
+        // Floats broadcast to colours, but colours don't narrow to floats.
+        assert_eq!(world.can_connect(float_out, color_in), Ok(()));
+        assert_eq!(world.can_connect(color_out, color_in), Ok(()));
+        assert_eq!(
+            world.can_connect(color_out, float_in),
+            Err(ConnectError::IncompatibleTypes {
+                from: ValueType::Color,
+                to: ValueType::Float
+            })
+        );
+
+        assert_eq!(world.connect(float_out, color_in), Ok(()));
+        assert!(
+            matches!(world.ports.get(color_in).connection_kind, PortKind::Input(Some(p)) if p == float_out)
+        );
+        assert!(world.connect(color_out, float_in).is_err());
+        assert!(matches!(
+            world.ports.get(float_in).connection_kind,
+            PortKind::Input(None)
+        ));
+    }
+
+    #[test]
+    fn links_cant_close_a_loop() {
+        let mut world = NodeWorld::new(Arc::new(basic_registry()));
+        let nodes: Vec<ID> = (0..3)
+            .map(|_| world.create_node(Default::default(), BINARY_MATH))
+            .collect();
+        let out = |world: &NodeWorld, n: usize| port(world, nodes[n], 2);
+        let input = |world: &NodeWorld, n: usize| port(world, nodes[n], 0);
+        world.connect(out(&world, 0), input(&world, 1)).unwrap();
+        world.connect(out(&world, 1), input(&world, 2)).unwrap();
+
+        assert_eq!(
+            world.can_connect(out(&world, 2), input(&world, 0)),
+            Err(ConnectError::Cycle)
+        );
+        assert_eq!(
+            world.can_connect(out(&world, 1), port(&world, nodes[0], 1)),
+            Err(ConnectError::Cycle)
+        );
+        // Feeding the same node twice is not a loop.
+        assert_eq!(
+            world.can_connect(out(&world, 0), port(&world, nodes[2], 1)),
+            Ok(())
+        );
+        assert!(world.validate().is_empty());
+    }
+
+    #[test]
+    fn validate_finds_loops_in_raw_links() {
+        let mut world = NodeWorld::new(Arc::new(basic_registry()));
+        let nodes: Vec<ID> = (0..3)
+            .map(|_| world.create_node(Default::default(), BINARY_MATH))
+            .collect();
+        // Linked the way loading does, without the checks `connect` makes.
+        let mut link = |from: usize, to: usize| {
+            let out = port(&world, nodes[from], 2);
+            let inp = port(&world, nodes[to], 0);
+            world.ports.get_mut(inp).connection_kind = PortKind::Input(Some(out));
+        };
+        link(0, 1);
+        link(1, 0);
+        link(1, 2);
+
+        let errors = world.validate();
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        let ValidationError::Cycle(cycle) = &errors[0] else {
+            panic!("expected a cycle, got {errors:?}");
+        };
+        assert_eq!(
+            cycle.iter().collect::<HashSet<_>>(),
+            HashSet::from([&nodes[0], &nodes[1]])
+        );
     }
 }
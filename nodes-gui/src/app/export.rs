@@ -0,0 +1,229 @@
+use std::{error::Error, fmt, path::Path};
+
+use crate::app::{
+    editor_graph::NodeWorld,
+    render::{self, Channel, RenderError, RenderSettings, Resolution},
+    storage::ID,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    Png,
+    // Binary graymap, for tools that don't read PNG.
+    Pgm,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorMode {
+    // Rec. 709 luma of the colour, alpha is dropped.
+    Gray,
+    Rgba,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Some(ImageFormat::Png),
+            "pgm" => Some(ImageFormat::Pgm),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Render(RenderError),
+    Io(std::io::Error),
+    Encode(png::EncodingError),
+    UnknownFormat(String),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Also says why evaluation failed, e.g. which attribute is missing.
+            ExportError::Render(e) => match e.source() {
+                Some(source) => write!(f, "{e}: {source}"),
+                None => write!(f, "{e}"),
+            },
+            ExportError::Io(e) => write!(f, "could not write image: {e}"),
+            ExportError::Encode(e) => write!(f, "could not encode image: {e}"),
+            ExportError::UnknownFormat(path) => {
+                write!(
+                    f,
+                    "unknown image format for \"{path}\" (expected .png or .pgm)"
+                )
+            }
+        }
+    }
+}
+
+impl Error for ExportError {}
+
+// `rgba` is laid out as `render::render_rgba` returns it. The bit depth is that of `T`.
+pub fn encode_png<T: Channel>(
+    rgba: &[T],
+    resolution: Resolution,
+    mode: ColorMode,
+) -> Result<Vec<u8>, ExportError> {
+    let mut out = Vec::new();
+    let mut encoder =
+        png::Encoder::new(&mut out, resolution.width as u32, resolution.height as u32);
+    encoder.set_color(match mode {
+        ColorMode::Gray => png::ColorType::Grayscale,
+        ColorMode::Rgba => png::ColorType::Rgba,
+    });
+    encoder.set_depth(match T::BITS {
+        8 => png::BitDepth::Eight,
+        _ => png::BitDepth::Sixteen,
+    });
+
+    let mut writer = encoder.write_header().map_err(ExportError::Encode)?;
+    writer
+        .write_image_data(&pixel_bytes(rgba, mode))
+        .map_err(ExportError::Encode)?;
+    writer.finish().map_err(ExportError::Encode)?;
+    Ok(out)
+}
+
+// Always gray, with the bit depth of `T`.
+pub fn encode_pgm<T: Channel>(rgba: &[T], resolution: Resolution) -> Vec<u8> {
+    let max = (1u32 << T::BITS) - 1;
+    let mut out = format!("P5\n{} {}\n{max}\n", resolution.width, resolution.height).into_bytes();
+    out.extend(pixel_bytes(rgba, ColorMode::Gray));
+    out
+}
+
+// Samples as big-endian bytes, the order both PNG and PGM store them in.
+fn pixel_bytes<T: Channel>(rgba: &[T], mode: ColorMode) -> Vec<u8> {
+    let mut out = Vec::new();
+    for pixel in rgba.chunks_exact(4) {
+        match mode {
+            ColorMode::Gray => {
+                let [r, g, b] = [0, 1, 2].map(|c| pixel[c].to_f32());
+                // Rounded, so gray inputs come back out unchanged.
+                let luma = 0.2126f32 * r + 0.7152f32 * g + 0.0722f32 * b;
+                T::from_f32(luma + 0.5f32 / ((1u32 << T::BITS) - 1) as f32).push_be_bytes(&mut out);
+            }
+            ColorMode::Rgba => pixel.iter().for_each(|c| c.push_be_bytes(&mut out)),
+        }
+    }
+    out
+}
+
+// Picks the format from the file extension. PGM files are always gray.
+pub fn write_image<T: Channel>(
+    path: &Path,
+    rgba: &[T],
+    resolution: Resolution,
+    mode: ColorMode,
+) -> Result<(), ExportError> {
+    let bytes = match ImageFormat::from_path(path) {
+        Some(ImageFormat::Png) => encode_png(rgba, resolution, mode)?,
+        Some(ImageFormat::Pgm) => encode_pgm(rgba, resolution),
+        None => return Err(ExportError::UnknownFormat(path.display().to_string())),
+    };
+    std::fs::write(path, bytes).map_err(ExportError::Io)
+}
+
+// Renders `output` and writes it to `path`, in the format its extension asks for.
+pub fn render_to_file(
+    world: &NodeWorld,
+    output: ID,
+    settings: &RenderSettings,
+    path: &Path,
+    depth: BitDepth,
+    mode: ColorMode,
+) -> Result<(), ExportError> {
+    fn render_and_write<T: Channel>(
+        world: &NodeWorld,
+        output: ID,
+        settings: &RenderSettings,
+        path: &Path,
+        mode: ColorMode,
+    ) -> Result<(), ExportError> {
+        let rgba =
+            render::render_rgba::<T>(world, output, settings).map_err(ExportError::Render)?;
+        write_image(path, &rgba, settings.resolution, mode)
+    }
+
+    match depth {
+        BitDepth::Eight => render_and_write::<u8>(world, output, settings, path, mode),
+        BitDepth::Sixteen => render_and_write::<u16>(world, output, settings, path, mode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decodes a PNG into its header and raw (big-endian) sample bytes.
+    fn decode(bytes: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+        let mut reader = png::Decoder::new(std::io::Cursor::new(bytes))
+            .read_info()
+            .unwrap();
+        let mut buf = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        buf.truncate(info.buffer_size());
+        (info, buf)
+    }
+
+    // A 3x2 image whose second pixel is opaque white and the rest transparent black.
+    fn image<T: Channel>() -> Vec<T> {
+        let mut rgba = vec![T::from_f32(0.0); 3 * 2 * 4];
+        rgba[4..8].fill(T::from_f32(1.0));
+        rgba
+    }
+
+    #[test]
+    fn png_8bit_rgba() {
+        let bytes = encode_png(&image::<u8>(), Resolution::new(3, 2), ColorMode::Rgba).unwrap();
+        let (info, data) = decode(&bytes);
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+        assert_eq!(info.bit_depth, png::BitDepth::Eight);
+        assert_eq!(data.len(), 3 * 2 * 4);
+        assert_eq!(&data[4..8], &[255; 4]);
+        assert_eq!(&data[8..12], &[0; 4]);
+    }
+
+    #[test]
+    fn png_16bit_rgba() {
+        let bytes = encode_png(&image::<u16>(), Resolution::new(3, 2), ColorMode::Rgba).unwrap();
+        let (info, data) = decode(&bytes);
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+        assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+        assert_eq!(data.len(), 3 * 2 * 4 * 2);
+        assert_eq!(&data[8..16], &[255; 8]);
+        assert_eq!(&data[16..24], &[0; 8]);
+    }
+
+    #[test]
+    fn png_8bit_gray() {
+        let bytes = encode_png(&image::<u8>(), Resolution::new(3, 2), ColorMode::Gray).unwrap();
+        let (info, data) = decode(&bytes);
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+        assert_eq!(info.bit_depth, png::BitDepth::Eight);
+        assert_eq!(data, [0, 255, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn png_16bit_gray() {
+        let bytes = encode_png(&image::<u16>(), Resolution::new(3, 2), ColorMode::Gray).unwrap();
+        let (info, data) = decode(&bytes);
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+        assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+        assert_eq!(data.len(), 3 * 2 * 2);
+        assert_eq!(&data[0..4], &[0, 0, 255, 255]);
+    }
+}
@@ -0,0 +1,566 @@
+// Formulas typed into Expression nodes, e.g. `sin(x * 10) * 0.5 + 0.5`. Every value is a
+// float; names not followed by `(` are variables supplied by whoever evaluates the formula.
+
+use std::fmt;
+
+use crate::app::basic_nodes::math;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+}
+
+impl Op {
+    pub fn apply(self, a: f32, b: f32) -> f32 {
+        match self {
+            Op::Add => math::add(a, b),
+            Op::Sub => math::sub(a, b),
+            Op::Mul => math::mul(a, b),
+            Op::Div => math::div(a, b),
+            Op::Rem => math::modulo(a, b),
+            Op::Pow => math::pow(a, b),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Func {
+    Sin,
+    Cos,
+    Pow,
+    Min,
+    Max,
+    Abs,
+    Floor,
+    Fract,
+    Clamp,
+    Mix,
+}
+
+impl Func {
+    const ALL: [Func; 10] = [
+        Func::Sin,
+        Func::Cos,
+        Func::Pow,
+        Func::Min,
+        Func::Max,
+        Func::Abs,
+        Func::Floor,
+        Func::Fract,
+        Func::Clamp,
+        Func::Mix,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Func::Sin => "sin",
+            Func::Cos => "cos",
+            Func::Pow => "pow",
+            Func::Min => "min",
+            Func::Max => "max",
+            Func::Abs => "abs",
+            Func::Floor => "floor",
+            Func::Fract => "fract",
+            Func::Clamp => "clamp",
+            Func::Mix => "mix",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Func> {
+        Func::ALL.into_iter().find(|f| f.name() == name)
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Func::Sin | Func::Cos | Func::Abs | Func::Floor | Func::Fract => 1,
+            Func::Pow | Func::Min | Func::Max => 2,
+            Func::Clamp | Func::Mix => 3,
+        }
+    }
+
+    // Takes exactly `arity` arguments. A plain function so it can be compiled into a
+    // `Compiler::call`. Each behaves like the Math node op of the same meaning.
+    pub fn function(self) -> fn(&[f32]) -> f32 {
+        match self {
+            Func::Sin => |a| math::sin(a[0]),
+            Func::Cos => |a| math::cos(a[0]),
+            Func::Pow => |a| math::pow(a[0], a[1]),
+            Func::Min => |a| math::min(a[0], a[1]),
+            Func::Max => |a| math::max(a[0], a[1]),
+            Func::Abs => |a| math::abs(a[0]),
+            Func::Floor => |a| math::floor(a[0]),
+            Func::Fract => |a| math::fract(a[0]),
+            Func::Clamp => |a| math::clamp(a[0], a[1], a[2]),
+            Func::Mix => |a| math::lerp(a[0], a[1], a[2]),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(f32),
+    Var(String),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+impl Expr {
+    // Every variable, in order of first appearance.
+    pub fn variables(&self) -> Vec<String> {
+        let mut vars = Vec::new();
+        self.collect_variables(&mut vars);
+        vars
+    }
+
+    fn collect_variables(&self, vars: &mut Vec<String>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Var(name) => {
+                if !vars.contains(name) {
+                    vars.push(name.clone());
+                }
+            }
+            Expr::Neg(e) => e.collect_variables(vars),
+            Expr::Binary(_, a, b) => {
+                a.collect_variables(vars);
+                b.collect_variables(vars);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|a| a.collect_variables(vars)),
+        }
+    }
+
+    // Fails with the first error `var` returns for one of the variables.
+    pub fn eval<E>(&self, var: &mut impl FnMut(&str) -> Result<f32, E>) -> Result<f32, E> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Var(name) => var(name)?,
+            Expr::Neg(e) => -e.eval(var)?,
+            Expr::Binary(op, a, b) => op.apply(a.eval(var)?, b.eval(var)?),
+            Expr::Call(f, args) => {
+                let args: Result<Vec<f32>, E> = args.iter().map(|a| a.eval(var)).collect();
+                f.function()(&args?)
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    // Character offset into the formula.
+    pub pos: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {}: {}", self.pos, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f32),
+    Ident(String),
+    Op(char),
+    Open,
+    Close,
+    Comma,
+    End,
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        let token = if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            // Exponent, as in `1e-3`.
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let number: String = chars[start..i].iter().collect();
+            Token::Number(number.parse().map_err(|_| ParseError {
+                pos: start,
+                message: format!("invalid number \"{number}\""),
+            })?)
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            Token::Ident(chars[start..i].iter().collect())
+        } else {
+            i += 1;
+            match c {
+                '+' | '-' | '*' | '/' | '%' | '^' => Token::Op(c),
+                '(' => Token::Open,
+                ')' => Token::Close,
+                ',' => Token::Comma,
+                _ => {
+                    return Err(ParseError {
+                        pos: start,
+                        message: format!("unexpected \"{c}\""),
+                    });
+                }
+            }
+        };
+        tokens.push((start, token));
+    }
+    tokens.push((chars.len(), Token::End));
+    Ok(tokens)
+}
+
+pub fn parse(text: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        next: 0,
+        depth: 0,
+    };
+    let expr = parser.sum()?;
+    match parser.peek() {
+        Token::End => Ok(expr),
+        _ => Err(parser.unexpected()),
+    }
+}
+
+// Deeper formulas are rejected, so that neither parsing nor evaluating them, which both recurse,
+// can overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+// Recursive descent, loosest binding first: `+ -`, then `* / %`, then unary minus, then `^`,
+// which groups to the right.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    // Depth of the expression tree being built, bounded by `MAX_DEPTH`.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].1
+    }
+
+    fn pos(&self) -> usize {
+        self.tokens[self.next].0
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.next].1.clone();
+        if token != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn unexpected(&self) -> ParseError {
+        let message = match self.peek() {
+            Token::End => "unexpected end of formula".to_string(),
+            Token::Number(n) => format!("unexpected number {n}"),
+            Token::Ident(name) => format!("unexpected \"{name}\""),
+            Token::Op(c) => format!("unexpected \"{c}\""),
+            Token::Open => "unexpected \"(\"".to_string(),
+            Token::Close => "unexpected \")\"".to_string(),
+            Token::Comma => "unexpected \",\"".to_string(),
+        };
+        ParseError {
+            pos: self.pos(),
+            message,
+        }
+    }
+
+    // Goes one level deeper. Callers reset `depth` once done with the level.
+    fn nest(&mut self) -> Result<(), ParseError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(ParseError {
+                pos: self.pos(),
+                message: "formula is nested too deeply".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), ParseError> {
+        if *self.peek() != token {
+            return Err(self.unexpected());
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn sum(&mut self) -> Result<Expr, ParseError> {
+        let depth = self.depth;
+        let mut expr = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Token::Op('+') => Op::Add,
+                Token::Op('-') => Op::Sub,
+                _ => {
+                    self.depth = depth;
+                    return Ok(expr);
+                }
+            };
+            // Each operator nests the terms before it one level deeper.
+            self.nest()?;
+            self.advance();
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, ParseError> {
+        let depth = self.depth;
+        let mut expr = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Token::Op('*') => Op::Mul,
+                Token::Op('/') => Op::Div,
+                Token::Op('%') => Op::Rem,
+                _ => {
+                    self.depth = depth;
+                    return Ok(expr);
+                }
+            };
+            self.nest()?;
+            self.advance();
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    // Every recursion of the parser passes through here.
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let depth = self.depth;
+        self.nest()?;
+        let expr = match self.peek() {
+            Token::Op('-') => {
+                self.advance();
+                Ok(Expr::Neg(Box::new(self.unary()?)))
+            }
+            Token::Op('+') => {
+                self.advance();
+                self.unary()
+            }
+            _ => self.power(),
+        };
+        self.depth = depth;
+        expr
+    }
+
+    fn power(&mut self) -> Result<Expr, ParseError> {
+        let base = self.atom()?;
+        if *self.peek() != Token::Op('^') {
+            return Ok(base);
+        }
+        self.advance();
+        // `-2^2` is -(2^2), but `2^-1` is allowed.
+        let exponent = self.unary()?;
+        Ok(Expr::Binary(Op::Pow, Box::new(base), Box::new(exponent)))
+    }
+
+    fn atom(&mut self) -> Result<Expr, ParseError> {
+        let pos = self.pos();
+        match self.peek().clone() {
+            Token::Number(n) => {
+                self.advance();
+                Ok(Expr::Number(n))
+            }
+            Token::Open => {
+                self.advance();
+                let expr = self.sum()?;
+                self.expect(Token::Close)?;
+                Ok(expr)
+            }
+            Token::Ident(name) => {
+                self.advance();
+                if *self.peek() != Token::Open {
+                    return Ok(Expr::Var(name));
+                }
+                let func = Func::from_name(&name).ok_or_else(|| ParseError {
+                    pos,
+                    message: format!("unknown function \"{name}\""),
+                })?;
+                self.advance();
+                let args = self.arguments()?;
+                if args.len() != func.arity() {
+                    return Err(ParseError {
+                        pos,
+                        message: format!(
+                            "{name} takes {} argument{}, got {}",
+                            func.arity(),
+                            if func.arity() == 1 { "" } else { "s" },
+                            args.len()
+                        ),
+                    });
+                }
+                Ok(Expr::Call(func, args))
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    // After the opening parenthesis, up to and including the closing one.
+    fn arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut args = Vec::new();
+        if *self.peek() == Token::Close {
+            self.advance();
+            return Ok(args);
+        }
+        loop {
+            args.push(self.sum()?);
+            match self.peek() {
+                Token::Comma => {}
+                Token::Close => {
+                    self.advance();
+                    return Ok(args);
+                }
+                _ => return Err(self.unexpected()),
+            }
+            self.advance();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> f32 {
+        parse(text)
+            .unwrap()
+            .eval(&mut |name| Err::<f32, _>(name.to_string()))
+            .unwrap()
+    }
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().message
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("10 - 4 - 3"), 3.0);
+        assert_eq!(eval("12 / 3 / 2"), 2.0);
+        assert_eq!(eval("2 * 3 ^ 2"), 18.0);
+        assert_eq!(eval("1 + 7 % 4 * 2"), 7.0);
+    }
+
+    #[test]
+    fn power_groups_to_the_right() {
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(eval("(2 ^ 3) ^ 2"), 64.0);
+        assert_eq!(eval("2 ^ -1"), 0.5);
+    }
+
+    #[test]
+    fn minus_binds_looser_than_power() {
+        assert_eq!(eval("-2 ^ 2"), -4.0);
+        assert_eq!(eval("(-2) ^ 2"), 4.0);
+        assert_eq!(
+            parse("-2^2").unwrap(),
+            Expr::Neg(Box::new(Expr::Binary(
+                Op::Pow,
+                Box::new(Expr::Number(2.0)),
+                Box::new(Expr::Number(2.0))
+            )))
+        );
+    }
+
+    #[test]
+    fn remainder_is_floored() {
+        assert_eq!(eval("7 % 3"), 1.0);
+        assert_eq!(eval("-7 % 3"), 2.0);
+        assert_eq!(eval("7 % -3"), -2.0);
+        assert_eq!(eval("5.5 % 2"), 1.5);
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("max(1, min(5, 3))"), 3.0);
+        assert_eq!(eval("clamp(2, 0, 1)"), 1.0);
+        assert_eq!(eval("mix(0, 10, 0.25)"), 2.5);
+        assert_eq!(error("sin(1, 2)"), "sin takes 1 argument, got 2");
+        assert_eq!(error("mix(1)"), "mix takes 3 arguments, got 1");
+        assert_eq!(error("pow()"), "pow takes 2 arguments, got 0");
+        let err = parse("1 + foo(2)").unwrap_err();
+        assert_eq!(
+            (err.pos, err.message.as_str()),
+            (4, "unknown function \"foo\"")
+        );
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(error("1 +"), "unexpected end of formula");
+        assert_eq!(error("(1"), "unexpected end of formula");
+        assert_eq!(error("1 2"), "unexpected number 2");
+        assert_eq!(error("1 $ 2"), "unexpected \"$\"");
+    }
+
+    #[test]
+    fn variables() {
+        let expr = parse("x * sin(y + x) - -t ^ 2 + pi").unwrap();
+        assert_eq!(expr.variables(), ["x", "y", "t", "pi"]);
+        assert!(parse("sin(1) + 2").unwrap().variables().is_empty());
+
+        let value = expr.eval(&mut |name| match name {
+            "x" => Ok(2.0),
+            "y" | "pi" => Ok(0.0),
+            "t" => Ok(3.0),
+            _ => Err(()),
+        });
+        assert_eq!(value, Ok(2.0 * math::sin(2.0) + 9.0));
+        assert_eq!(
+            parse("z").unwrap().eval(&mut |_| Err("missing")),
+            Err("missing")
+        );
+    }
+
+    #[test]
+    fn nesting_depth_is_limited() {
+        let nested = |n| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert_eq!(eval(&nested(50)), 1.0);
+        assert_eq!(error(&nested(100_000)), "formula is nested too deeply");
+        assert_eq!(error(&"-".repeat(100_000)), "formula is nested too deeply");
+        assert_eq!(error(&"2^".repeat(100_000)), "formula is nested too deeply");
+        assert_eq!(
+            error(&"sin(".repeat(100_000)),
+            "formula is nested too deeply"
+        );
+
+        // Long chains build deep trees without the parser recursing.
+        assert_eq!(eval(&vec!["1"; 100].join(" + ")), 100.0);
+        assert_eq!(
+            error(&vec!["1"; 100_000].join(" + ")),
+            "formula is nested too deeply"
+        );
+        assert_eq!(
+            error(&vec!["1"; 100_000].join(" * ")),
+            "formula is nested too deeply"
+        );
+    }
+}
@@ -0,0 +1,458 @@
+// Group nodes wrap a whole `NodeWorld`. The Group Input nodes inside it become the group
+// node's inputs and its Group Output nodes the outputs, named and typed by their state.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::Path,
+    sync::Arc,
+};
+
+use egui::{Pos2, vec2};
+
+use crate::app::{
+    basic_nodes::{
+        group_io::{GROUP_INPUT, GROUP_OUTPUT, io_name, io_type},
+        node_tools::{get_input, get_state_string_mut},
+    },
+    editor_graph::{
+        EvalContext, EvalError, EvalErrorKind, Layout, NodePrototype, NodeState, NodeWorld,
+        PortKind, PortKindPrototype, PortPrototype, StateValue,
+    },
+    interpreter::{Compiler, Register},
+    registry::PrototypeRegistry,
+    serialization::{self, LoadError, SaveError},
+    storage::ID,
+    value::{Value, ValueType},
+};
+
+// Group assets are saved here as one graph file each, named after the asset.
+pub const ASSET_DIR: &str = "assets";
+
+// Key of groups made in the editor. Assets are keyed by `asset_key` instead, so every instance
+// of one asset shares its name.
+pub const GROUP: &str = "group";
+const ASSET_PREFIX: &str = "asset/";
+pub const ASSET_CATEGORY: &str = "Assets";
+
+pub fn asset_key(name: &str) -> String {
+    format!("{ASSET_PREFIX}{name}")
+}
+
+// Where the tree-walker passes a group's input values to its Group Input nodes. The leading
+// NUL keeps it from clashing with attribute names typed into the editor.
+pub fn input_key(name: &str) -> String {
+    format!("\0group input {name}")
+}
+
+fn io_nodes(world: &NodeWorld, kind: &str) -> Vec<ID> {
+    let mut nodes: Vec<ID> = world
+        .nodes
+        .with_ids()
+        .into_iter()
+        .filter(|(_, n)| n.key == kind)
+        .map(|(id, _)| *id)
+        .collect();
+    // Ports follow the on-screen order of the nodes they come from.
+    nodes.sort_by(|a, b| {
+        let (a, b) = (world.nodes.get(*a).pos, world.nodes.get(*b).pos);
+        a.y.total_cmp(&b.y).then(a.x.total_cmp(&b.x))
+    });
+    nodes
+}
+
+// Ports of one direction, where only the first of several nodes with the same name counts.
+fn io_ports(
+    world: &NodeWorld,
+    kind: &str,
+    x: f32,
+    kind_proto: PortKindPrototype,
+) -> Vec<PortPrototype> {
+    let mut seen = HashSet::new();
+    io_nodes(world, kind)
+        .into_iter()
+        .filter_map(|id| {
+            let state = &world.nodes.get(id).state.state;
+            let (name, ty) = (io_name(state)?, io_type(state)?);
+            seen.insert(name.to_string()).then(|| PortPrototype {
+                local_position: vec2(x, 30f32 + 15f32 * seen.len() as f32),
+                name: name.to_string(),
+                kind: kind_proto.clone(),
+                ty,
+            })
+        })
+        .collect()
+}
+
+// The ports of a group node with `world` as its graph.
+fn group_layout(world: &NodeWorld) -> Layout {
+    const WIDTH: f32 = 150f32;
+
+    let inputs = io_ports(world, GROUP_INPUT, 0f32, PortKindPrototype::Input);
+    let outputs = io_ports(
+        world,
+        GROUP_OUTPUT,
+        WIDTH,
+        PortKindPrototype::Output(eval_group_output, compile_group_output),
+    );
+    let rows = inputs.len().max(outputs.len());
+    Layout {
+        ports: inputs.into_iter().chain(outputs).collect(),
+        size: vec2(WIDTH, 60f32 + 15f32 * rows as f32),
+    }
+}
+
+// Groups made in the editor are registered under `GROUP` with an empty graph, each node
+// holding its own. Assets are registered with theirs, shared by every instance.
+pub fn group_prototype(key: &str, world: NodeWorld) -> NodePrototype {
+    let layout = group_layout(&world);
+    NodePrototype {
+        key: key.to_string(),
+        name: key
+            .strip_prefix(ASSET_PREFIX)
+            .unwrap_or("Group")
+            .to_string(),
+        ports: layout.ports,
+        state_prototype: NodeState::default(),
+        size: layout.size,
+        subgraph: Some(Arc::new(world)),
+    }
+}
+
+// Never called: `NodeWorld::evaluate_output_port` and `Compiler::output_port` evaluate group
+// nodes from their subgraph instead.
+fn eval_group_output(
+    _: &NodeWorld,
+    _: &HashMap<String, Option<ID>>,
+    _: &HashMap<String, StateValue>,
+    _: EvalContext,
+) -> Result<Value, EvalError> {
+    Err(EvalErrorKind::NotAnOutput.into())
+}
+
+fn compile_group_output(
+    _: &mut Compiler,
+    _: &HashMap<String, Option<ID>>,
+    _: &HashMap<String, StateValue>,
+) -> Option<Register> {
+    None
+}
+
+// The port inside `inner` feeding the Group Output named `output`, and the names of the group
+// inputs it depends on. Only those are evaluated, like inputs of other nodes are only read
+// when needed.
+pub fn output_source(inner: &NodeWorld, output: &str) -> Option<(ID, Vec<String>)> {
+    let out_node = io_nodes(inner, GROUP_OUTPUT)
+        .into_iter()
+        .find(|id| io_name(&inner.nodes.get(*id).state.state) == Some(output))?;
+    let source = match inner
+        .ports
+        .get(inner.nodes.get(out_node).ports[0])
+        .connection_kind
+    {
+        PortKind::Input(Some(source)) => source,
+        _ => return None,
+    };
+
+    let mut used = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![out_node];
+    while let Some(n) = stack.pop() {
+        if !visited.insert(n) {
+            continue;
+        }
+        let node = inner.nodes.get(n);
+        if node.key == GROUP_INPUT
+            && let Some(name) = io_name(&node.state.state)
+            && !used.iter().any(|u| u == name)
+        {
+            used.push(name.to_string());
+        }
+        stack.extend(inner.upstream_nodes(n));
+    }
+    Some((source, used))
+}
+
+// Evaluates the group node `node`'s output named `output` by evaluating its subgraph, with the
+// group's inputs passed to the Group Input nodes through the context. Errors inside the
+// subgraph are reported for the group node, its ports mean nothing outside.
+pub fn evaluate_output(
+    world: &NodeWorld,
+    node: ID,
+    output: &str,
+    ctx: EvalContext,
+) -> Result<Value, EvalError> {
+    let missing = || EvalError::new(EvalErrorKind::MissingGroupOutput(output.to_string()));
+    let inner = world.subgraph(world.nodes.get(node)).ok_or_else(missing)?;
+    let (source, used_inputs) = output_source(inner, output).ok_or_else(missing)?;
+
+    let inputs = world.direct_inputs(node);
+    let mut inner_ctx = ctx.clone();
+    for name in used_inputs {
+        let val = get_input(&name, world, &inputs, &ctx)?;
+        inner_ctx.insert_mut(input_key(&name), val);
+    }
+    inner
+        .evaluate_group_output_port(node, source, inner_ctx)
+        .map_err(|e| EvalError::new(e.kind))
+}
+
+// Applies the type picked on a Group Input or Output node to its port.
+pub fn sync_io_type(world: &mut NodeWorld, node: ID) {
+    let n = world.nodes.get(node);
+    if n.key != GROUP_INPUT && n.key != GROUP_OUTPUT {
+        return;
+    }
+    if let Some(ty) = io_type(&n.state.state) {
+        let port = n.ports[0];
+        if world.port_info(port).ty != ty {
+            world.set_port_type(port, ty);
+        }
+    }
+}
+
+// Replaces the subgraph of the group node `node` with `inner`, e.g. after editing it. Ports
+// that are still there keep their links. An edited asset instance keeps its key but no longer
+// follows the asset.
+pub fn apply_edit(outer: &mut NodeWorld, node: ID, inner: NodeWorld) {
+    let layout = group_layout(&inner);
+    outer.nodes.get_mut(node).subgraph = Some(Arc::new(inner));
+    outer.set_layout(node, Some(layout));
+}
+
+// Turns the group node `node` into an instance of the asset registered under `key`.
+pub fn bind_asset(world: &mut NodeWorld, node: ID, key: &str) {
+    let n = world.nodes.get_mut(node);
+    n.key = key.to_string();
+    n.subgraph = None;
+    world.set_layout(node, None);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupError {
+    NothingSelected,
+    // Some node outside the selection both reads from it and feeds into it.
+    Cycle,
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupError::NothingSelected => write!(f, "No nodes selected"),
+            GroupError::Cycle => write!(
+                f,
+                "A node outside the selection is both upstream and downstream of it"
+            ),
+        }
+    }
+}
+
+// Moves `selected` into a new group node and returns it. Links crossing the selection become
+// group inputs and outputs named after the ports they connect, so the graph computes the same
+// as before.
+pub fn group_nodes(world: &mut NodeWorld, selected: &[ID]) -> Result<ID, GroupError> {
+    let selected: Vec<ID> = world
+        .nodes
+        .ids()
+        .copied()
+        .filter(|id| selected.contains(id))
+        .collect();
+    if selected.is_empty() {
+        return Err(GroupError::NothingSelected);
+    }
+    let outside: Vec<ID> = world
+        .nodes
+        .ids()
+        .copied()
+        .filter(|id| !selected.contains(id))
+        .collect();
+    for n in &outside {
+        let downstream = selected.iter().any(|s| world.is_upstream_of(*s, *n));
+        let upstream = selected.iter().any(|s| world.is_upstream_of(*n, *s));
+        if downstream && upstream {
+            return Err(GroupError::Cycle);
+        }
+    }
+
+    let mut inner = NodeWorld::new(world.registry().clone());
+    let mut port_map = HashMap::new();
+    for id in &selected {
+        let n = world.nodes.get(*id);
+        let copy = inner.create_node(n.pos, &n.key);
+        inner.nodes.get_mut(copy).state = n.state.clone();
+        inner.nodes.get_mut(copy).subgraph = n.subgraph.clone();
+        inner.set_layout(copy, n.layout.clone());
+        for (old, new) in n.ports.iter().zip(inner.nodes.get(copy).ports.clone()) {
+            port_map.insert(*old, new);
+        }
+    }
+
+    let min = selected
+        .iter()
+        .map(|id| world.nodes.get(*id).pos)
+        .fold(Pos2::new(f32::INFINITY, f32::INFINITY), |a, b| a.min(b));
+    let max = selected
+        .iter()
+        .map(|id| {
+            let n = world.nodes.get(*id);
+            n.pos + world.node_size(n)
+        })
+        .fold(Pos2::new(f32::NEG_INFINITY, f32::NEG_INFINITY), |a, b| {
+            a.max(b)
+        });
+
+    let mut names = HashSet::new();
+    let mut unique_name = |name: &str| {
+        let base = if name.is_empty() { "value" } else { name };
+        let mut candidate = base.to_string();
+        let mut i = 2;
+        while !names.insert(candidate.clone()) {
+            candidate = format!("{base} {i}");
+            i += 1;
+        }
+        candidate
+    };
+
+    // Outside output port -> group input reading it.
+    let mut group_inputs: Vec<(ID, String)> = Vec::new();
+    for id in &selected {
+        for p in &world.nodes.get(*id).ports {
+            let port = world.ports.get(*p);
+            let PortKind::Input(Some(out)) = port.connection_kind else {
+                continue;
+            };
+            let Some(out_port) = world.ports.try_get(out) else {
+                continue;
+            };
+            let source = if selected.contains(&out_port.node) {
+                port_map[&out]
+            } else {
+                let name = match group_inputs.iter().find(|(o, _)| *o == out) {
+                    Some((_, name)) => name.clone(),
+                    None => {
+                        let name = unique_name(&world.port_info(*p).name);
+                        group_inputs.push((out, name.clone()));
+                        name
+                    }
+                };
+                io_node(
+                    &mut inner,
+                    true,
+                    &name,
+                    world.port_info(out).ty,
+                    group_inputs.len(),
+                    min.x - 200f32,
+                    min.y,
+                )
+            };
+            inner.ports.get_mut(port_map[p]).connection_kind = PortKind::Input(Some(source));
+        }
+    }
+
+    // Selected output port -> group output exposing it, and the outside inputs reading it.
+    let mut group_outputs: Vec<(ID, String, Vec<ID>)> = Vec::new();
+    for (id, port) in world.ports.with_ids() {
+        let PortKind::Input(Some(out)) = port.connection_kind else {
+            continue;
+        };
+        if selected.contains(&port.node) || !port_map.contains_key(&out) {
+            continue;
+        }
+        match group_outputs.iter_mut().find(|(o, ..)| *o == out) {
+            Some((_, _, readers)) => readers.push(*id),
+            None => {
+                let name = unique_name(&world.port_info(out).name);
+                let sink = io_node(
+                    &mut inner,
+                    false,
+                    &name,
+                    world.port_info(out).ty,
+                    group_outputs.len() + 1,
+                    max.x + 50f32,
+                    min.y,
+                );
+                inner.ports.get_mut(sink).connection_kind = PortKind::Input(Some(port_map[&out]));
+                group_outputs.push((out, name, vec![*id]));
+            }
+        }
+    }
+
+    for id in &selected {
+        world.remove_node(*id);
+    }
+    let group = world.create_node(min, GROUP);
+    apply_edit(world, group, inner);
+    let port_named = |world: &NodeWorld, name: &str, input: bool| {
+        world.nodes.get(group).ports.iter().copied().find(|p| {
+            world.port_info(*p).name == name
+                && world.ports.get(*p).connection_kind.is_input() == input
+        })
+    };
+    for (out, name) in group_inputs {
+        if let Some(inp) = port_named(world, &name, true) {
+            world.ports.get_mut(inp).connection_kind = PortKind::Input(Some(out));
+        }
+    }
+    for (_, name, readers) in group_outputs {
+        if let Some(out) = port_named(world, &name, false) {
+            for inp in readers {
+                world.ports.get_mut(inp).connection_kind = PortKind::Input(Some(out));
+            }
+        }
+    }
+
+    Ok(group)
+}
+
+// Adds a Group Input or Output node to `world`, returning the port that links to the rest of
+// the graph.
+fn io_node(
+    world: &mut NodeWorld,
+    input: bool,
+    name: &str,
+    ty: ValueType,
+    row: usize,
+    x: f32,
+    y: f32,
+) -> ID {
+    let key = if input { GROUP_INPUT } else { GROUP_OUTPUT };
+    let pos = Pos2::new(x, y + 120f32 * (row as f32 - 1f32));
+    let id = world.create_node(pos, key);
+    let state = &mut world.nodes.get_mut(id).state.state;
+    *get_state_string_mut("name", state).unwrap() = name.to_string();
+    *get_state_string_mut("type", state).unwrap() = ty.name().to_string();
+    sync_io_type(world, id);
+    world.nodes.get(id).ports[0]
+}
+
+pub fn save_asset(name: &str, inner: &NodeWorld) -> Result<(), SaveError> {
+    let dir = Path::new(ASSET_DIR);
+    std::fs::create_dir_all(dir).map_err(SaveError::Io)?;
+    serialization::save_world_to_file(inner, &dir.join(format!("{name}.ron")))
+}
+
+// Every group asset in `ASSET_DIR` as a prototype named after its file, along with the files
+// that couldn't be loaded.
+pub fn load_assets(
+    registry: &Arc<PrototypeRegistry>,
+) -> (Vec<NodePrototype>, Vec<(String, LoadError)>) {
+    let mut assets = Vec::new();
+    let mut errors = Vec::new();
+    let Ok(entries) = std::fs::read_dir(ASSET_DIR) else {
+        return (assets, errors);
+    };
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "ron"))
+        .collect();
+    paths.sort();
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        match serialization::load_world_from_file(&path, registry) {
+            Ok(inner) => assets.push(group_prototype(&asset_key(&name), inner)),
+            Err(e) => errors.push((name, e)),
+        }
+    }
+    (assets, errors)
+}
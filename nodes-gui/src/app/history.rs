@@ -0,0 +1,77 @@
+use crate::app::{editor_graph::NodeWorld, storage::ID};
+
+// Oldest snapshots are dropped past this many undo steps. Snapshots share their unchanged
+// nodes and ports, so each one costs about as much as the edit it undoes.
+const MAX_ENTRIES: usize = 1000;
+
+// Identifies an ongoing edit whose consecutive changes should undo as a single step.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MergeKey {
+    Link,
+    NodeState(ID),
+}
+
+// Undo/redo stacks of `NodeWorld` snapshots taken before each edit.
+#[derive(Default)]
+pub struct History {
+    undo: Vec<NodeWorld>,
+    redo: Vec<NodeWorld>,
+    open_edit: Option<MergeKey>,
+}
+
+impl History {
+    pub fn record(&mut self, before: &NodeWorld) {
+        self.push(before.clone());
+        self.open_edit = None;
+    }
+
+    // Only the first change of a run with the same key takes a snapshot, until `close_edit`.
+    pub fn record_merged(&mut self, key: MergeKey, before: &NodeWorld) {
+        if self.open_edit != Some(key) {
+            self.push(before.clone());
+            self.open_edit = Some(key);
+        }
+    }
+
+    pub fn close_edit(&mut self) {
+        self.open_edit = None;
+    }
+
+    fn push(&mut self, snapshot: NodeWorld) {
+        if self.undo.len() == MAX_ENTRIES {
+            self.undo.remove(0);
+        }
+        self.undo.push(snapshot);
+        self.redo.clear();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn undo(&mut self, world: &mut NodeWorld) -> bool {
+        self.open_edit = None;
+        match self.undo.pop() {
+            Some(previous) => {
+                self.redo.push(std::mem::replace(world, previous));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self, world: &mut NodeWorld) -> bool {
+        self.open_edit = None;
+        match self.redo.pop() {
+            Some(next) => {
+                self.undo.push(std::mem::replace(world, next));
+                true
+            }
+            None => false,
+        }
+    }
+}
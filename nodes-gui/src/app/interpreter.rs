@@ -1 +1,409 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
 
+use crate::app::{
+    editor_graph::{NodeWorld, PortKindPrototype},
+    group,
+    storage::ID,
+    value::{Value, ValueType},
+};
+
+// Calls with more arguments than this are rejected at compile time so the interpreter can
+// gather arguments on the stack.
+const MAX_CALL_ARGS: usize = 8;
+
+// Every instruction writes exactly one register, the one with the same index as the instruction.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Register(usize);
+
+impl Register {
+    // Where the register's value is in the slice `Program::run` fills.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinaryOp {
+    pub fn apply(self, a: f32, b: f32) -> f32 {
+        match self {
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+        }
+    }
+}
+
+// `Binary` and `Call` only ever read registers the compiler has made sure hold floats.
+#[derive(Clone)]
+pub enum Instruction {
+    Const(Value),
+    // Index into `Program::attributes`.
+    Attribute(usize),
+    Convert(Register, ValueType),
+    Binary(BinaryOp, Register, Register),
+    Call(fn(&[f32]) -> f32, Box<[Register]>),
+    CallValue(fn(&[Value]) -> Option<Value>, Box<[Register]>),
+}
+
+impl Instruction {
+    // The registers the instruction reads.
+    fn args(&self) -> Vec<Register> {
+        match self {
+            Instruction::Const(_) | Instruction::Attribute(_) => Vec::new(),
+            Instruction::Convert(a, _) => vec![*a],
+            Instruction::Binary(_, a, b) => vec![*a, *b],
+            Instruction::Call(_, args) | Instruction::CallValue(_, args) => args.to_vec(),
+        }
+    }
+}
+
+fn float(v: Value) -> Option<f32> {
+    match v {
+        Value::Float(f) => Some(f),
+        _ => None,
+    }
+}
+
+// A graph flattened into a topologically sorted list of instructions.
+#[derive(Clone)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    attributes: Vec<String>,
+    output: Register,
+    // Whether each instruction has to run for every pixel, see `hoist`.
+    varying: Vec<bool>,
+}
+
+impl Program {
+    // Names of the context attributes the program reads, in slot order.
+    pub fn attributes(&self) -> &[String] {
+        &self.attributes
+    }
+
+    pub fn attribute_slot(&self, name: &str) -> Option<usize> {
+        self.attributes.iter().position(|a| a == name)
+    }
+
+    pub fn new_registers(&self) -> Vec<Value> {
+        vec![Value::Float(0f32); self.instructions.len()]
+    }
+
+    // `registers` must come from `new_registers`, `attributes` holds one value per slot.
+    // Fails where the tree-walker would, e.g. on attribute values of the wrong type.
+    pub fn run(&self, registers: &mut [Value], attributes: &[Value]) -> Option<Value> {
+        for dst in 0..self.instructions.len() {
+            registers[dst] = self.execute(dst, registers, attributes)?;
+        }
+        Some(registers[self.output.0])
+    }
+
+    // The attribute slots each register's value depends on, directly or through the registers
+    // it is computed from.
+    pub fn register_attributes(&self) -> Vec<BTreeSet<usize>> {
+        let mut deps: Vec<BTreeSet<usize>> = Vec::with_capacity(self.instructions.len());
+        for instr in &self.instructions {
+            let mut slots = BTreeSet::new();
+            if let Instruction::Attribute(slot) = instr {
+                slots.insert(*slot);
+            }
+            for a in instr.args() {
+                slots.extend(&deps[a.0]);
+            }
+            deps.push(slots);
+        }
+        deps
+    }
+
+    // Splits the program into the instructions reading any of the attribute `slots`, directly
+    // or not, and the rest, which compute the same value as long as only those slots change.
+    pub fn hoist(&mut self, slots: &[usize]) {
+        self.varying = self
+            .register_attributes()
+            .iter()
+            .map(|deps| slots.iter().any(|s| deps.contains(s)))
+            .collect();
+    }
+
+    // Runs the instructions `hoist` found not to depend on the varying slots.
+    pub fn run_uniform(&self, registers: &mut [Value], attributes: &[Value]) -> Option<()> {
+        for dst in (0..self.instructions.len()).filter(|i| !self.varying[*i]) {
+            registers[dst] = self.execute(dst, registers, attributes)?;
+        }
+        Some(())
+    }
+
+    // Like `run`, for `registers` that `run_uniform` was called on with the same values of
+    // all but the varying slots.
+    pub fn run_varying(&self, registers: &mut [Value], attributes: &[Value]) -> Option<Value> {
+        for dst in (0..self.instructions.len()).filter(|i| self.varying[*i]) {
+            registers[dst] = self.execute(dst, registers, attributes)?;
+        }
+        Some(registers[self.output.0])
+    }
+
+    fn execute(&self, dst: usize, registers: &[Value], attributes: &[Value]) -> Option<Value> {
+        Some(match &self.instructions[dst] {
+            Instruction::Const(v) => *v,
+            Instruction::Attribute(slot) => attributes[*slot],
+            Instruction::Convert(a, ty) => registers[a.0].convert(*ty)?,
+            Instruction::Binary(op, a, b) => {
+                Value::Float(op.apply(float(registers[a.0])?, float(registers[b.0])?))
+            }
+            Instruction::Call(f, args) => {
+                let mut buf = [0f32; MAX_CALL_ARGS];
+                for (v, a) in buf.iter_mut().zip(args.iter()) {
+                    *v = float(registers[a.0])?;
+                }
+                Value::Float(f(&buf[..args.len()]))
+            }
+            Instruction::CallValue(f, args) => {
+                let mut buf = [Value::Float(0f32); MAX_CALL_ARGS];
+                for (v, a) in buf.iter_mut().zip(args.iter()) {
+                    *v = registers[a.0];
+                }
+                f(&buf[..args.len()])?
+            }
+        })
+    }
+}
+
+pub struct Compiler<'a> {
+    world: &'a NodeWorld,
+    instructions: Vec<Instruction>,
+    // Type of each register where it is known at compile time.
+    types: Vec<Option<ValueType>>,
+    attributes: Vec<String>,
+    compiled_ports: HashMap<ID, Register>,
+    visiting: HashSet<ID>,
+    // Registers holding the inputs of the group whose graph is being compiled.
+    group_inputs: HashMap<String, Register>,
+    // Attributes replaced by other values for the ports being compiled, see `with_attributes`.
+    overridden: HashMap<String, Register>,
+    // Ports whose values are supplied per pixel through the attribute of the given name instead
+    // of being computed, e.g. from an image rendered earlier.
+    precomputed: HashMap<ID, String>,
+}
+
+impl<'a> Compiler<'a> {
+    fn push(&mut self, instr: Instruction, ty: Option<ValueType>) -> Register {
+        self.instructions.push(instr);
+        self.types.push(ty);
+        Register(self.instructions.len() - 1)
+    }
+
+    pub fn constant(&mut self, val: Value) -> Register {
+        self.push(Instruction::Const(val), Some(val.value_type()))
+    }
+
+    // Emits a conversion unless the register is already known to hold `ty`. Fails at compile
+    // time if the known type can't be converted.
+    pub fn convert(&mut self, reg: Register, ty: ValueType) -> Option<Register> {
+        match self.types[reg.0] {
+            Some(known) if known == ty => Some(reg),
+            Some(known) if !known.converts_to(ty) => None,
+            _ => Some(self.push(Instruction::Convert(reg, ty), Some(ty))),
+        }
+    }
+
+    pub fn attribute(&mut self, name: &str) -> Register {
+        if let Some(reg) = self.overridden.get(name) {
+            return *reg;
+        }
+        let slot = match self.attributes.iter().position(|a| a == name) {
+            Some(slot) => slot,
+            None => {
+                self.attributes.push(name.to_string());
+                self.attributes.len() - 1
+            }
+        };
+        self.push(Instruction::Attribute(slot), None)
+    }
+
+    pub fn binary(&mut self, op: BinaryOp, a: Register, b: Register) -> Option<Register> {
+        let a = self.convert(a, ValueType::Float)?;
+        let b = self.convert(b, ValueType::Float)?;
+        Some(self.push(Instruction::Binary(op, a, b), Some(ValueType::Float)))
+    }
+
+    pub fn call(&mut self, f: fn(&[f32]) -> f32, args: &[Register]) -> Option<Register> {
+        if args.len() > MAX_CALL_ARGS {
+            return None;
+        }
+        let args: Option<Box<[Register]>> = args
+            .iter()
+            .map(|a| self.convert(*a, ValueType::Float))
+            .collect();
+        Some(self.push(Instruction::Call(f, args?), Some(ValueType::Float)))
+    }
+
+    // For nodes working on typed values. `ty` is the result type if it is always the same.
+    pub fn call_value(
+        &mut self,
+        f: fn(&[Value]) -> Option<Value>,
+        args: &[Register],
+        ty: Option<ValueType>,
+    ) -> Option<Register> {
+        if args.len() > MAX_CALL_ARGS {
+            return None;
+        }
+        Some(self.push(Instruction::CallValue(f, args.into()), ty))
+    }
+
+    // Compile-time counterpart of `node_tools::get_input`: a connected input compiles the
+    // upstream port, an unconnected one reads the context attribute of the same name.
+    pub fn input(&mut self, name: &str, inputs: &HashMap<String, Option<ID>>) -> Option<Register> {
+        if let Some(Some(id)) = inputs.get(name) {
+            return self.output_port(*id);
+        }
+
+        Some(self.attribute(name))
+    }
+
+    pub fn input_as(
+        &mut self,
+        name: &str,
+        ty: ValueType,
+        inputs: &HashMap<String, Option<ID>>,
+    ) -> Option<Register> {
+        let reg = self.input(name, inputs)?;
+        self.convert(reg, ty)
+    }
+
+    // Ports are compiled once and in dependency order, so shared upstream nodes are only
+    // emitted once and every register is written before it is read.
+    pub fn output_port(&mut self, id: ID) -> Option<Register> {
+        if let Some(r) = self.compiled_ports.get(&id) {
+            return Some(*r);
+        }
+        let world = self.world;
+        let port = world.ports.try_get(id)?;
+        let info = world.port_info(id);
+
+        if let Some(name) = self.precomputed.get(&id) {
+            let name = name.clone();
+            let reg = self.attribute(&name);
+            self.types[reg.0] = Some(info.ty);
+            self.compiled_ports.insert(id, reg);
+            return Some(reg);
+        }
+
+        if !self.visiting.insert(id) {
+            return None;
+        }
+
+        let PortKindPrototype::Output(_, compile) = info.kind else {
+            return None;
+        };
+
+        let node = world.nodes.get(port.node);
+        let reg = if world.subgraph(node).is_some() {
+            self.group_output(port.node, &info.name)
+        } else {
+            compile(self, &world.direct_inputs(port.node), &node.state.state)
+        };
+
+        self.visiting.remove(&id);
+        let reg = self.convert(reg?, info.ty)?;
+        self.compiled_ports.insert(id, reg);
+        Some(reg)
+    }
+
+    // Compile-time counterpart of `group::evaluate_output`. The group's graph is compiled in a
+    // scope of its own, since its port IDs mean nothing in the surrounding graph.
+    fn group_output(&mut self, node: ID, output: &str) -> Option<Register> {
+        let world = self.world;
+        let inner: &'a NodeWorld = world.subgraph(world.nodes.get(node))?;
+        let (source, used_inputs) = group::output_source(inner, output)?;
+
+        let inputs = world.direct_inputs(node);
+        let mut bindings = HashMap::new();
+        for name in used_inputs {
+            let reg = self.input(&name, &inputs)?;
+            bindings.insert(name, reg);
+        }
+
+        let outer_world = std::mem::replace(&mut self.world, inner);
+        let outer_ports = std::mem::take(&mut self.compiled_ports);
+        let outer_visiting = std::mem::take(&mut self.visiting);
+        let outer_inputs = std::mem::replace(&mut self.group_inputs, bindings);
+        let outer_precomputed = std::mem::take(&mut self.precomputed);
+
+        let reg = self.output_port(source);
+
+        self.world = outer_world;
+        self.compiled_ports = outer_ports;
+        self.visiting = outer_visiting;
+        self.group_inputs = outer_inputs;
+        self.precomputed = outer_precomputed;
+        reg
+    }
+
+    // Compile-time counterpart of evaluating with a modified context: while `f` runs, reading
+    // the attributes in `bindings` yields their registers instead. Ports compiled inside are
+    // kept apart from the rest, since the same port can compute something else here.
+    pub fn with_attributes(
+        &mut self,
+        bindings: HashMap<String, Register>,
+        f: impl FnOnce(&mut Self) -> Option<Register>,
+    ) -> Option<Register> {
+        let mut overridden = self.overridden.clone();
+        overridden.extend(bindings);
+        let outer_overridden = std::mem::replace(&mut self.overridden, overridden);
+        let outer_ports = std::mem::take(&mut self.compiled_ports);
+        let outer_precomputed = std::mem::take(&mut self.precomputed);
+
+        let reg = f(self);
+
+        self.overridden = outer_overridden;
+        self.compiled_ports = outer_ports;
+        self.precomputed = outer_precomputed;
+        reg
+    }
+
+    // The register bound to the input of the enclosing group named `name`.
+    pub fn group_input(&self, name: &str) -> Option<Register> {
+        self.group_inputs.get(name).copied()
+    }
+}
+
+pub fn compile(world: &NodeWorld, output: ID) -> Option<Program> {
+    compile_with_precomputed(world, output, HashMap::new()).map(|(program, _)| program)
+}
+
+// Like `compile`, but reads the ports in `precomputed` from attributes, see
+// `Compiler::precomputed`. Also returns the register of every port of `world` the program
+// computes, to capture their values while it runs.
+pub fn compile_with_precomputed(
+    world: &NodeWorld,
+    output: ID,
+    precomputed: HashMap<ID, String>,
+) -> Option<(Program, HashMap<ID, Register>)> {
+    let mut compiler = Compiler {
+        world,
+        instructions: Vec::new(),
+        types: Vec::new(),
+        attributes: Vec::new(),
+        compiled_ports: HashMap::new(),
+        visiting: HashSet::new(),
+        group_inputs: HashMap::new(),
+        overridden: HashMap::new(),
+        precomputed,
+    };
+    let output = compiler.output_port(output)?;
+
+    let program = Program {
+        varying: vec![true; compiler.instructions.len()],
+        instructions: compiler.instructions,
+        attributes: compiler.attributes,
+        output,
+    };
+    Some((program, compiler.compiled_ports))
+}
@@ -0,0 +1,67 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::app::editor_graph::NodePrototype;
+
+#[derive(Clone)]
+pub struct RegistryEntry {
+    pub category: String,
+    pub prototype: Arc<NodePrototype>,
+}
+
+// Every prototype nodes can be created from, by key. Placed nodes only store the key and look
+// their prototype up through the registry of their world, so a key identifies what kind of node
+// something is regardless of its display name.
+#[derive(Clone, Default)]
+pub struct PrototypeRegistry {
+    // In menu order.
+    entries: Vec<RegistryEntry>,
+    by_key: HashMap<String, usize>,
+}
+
+impl PrototypeRegistry {
+    // Adds a prototype under its key, replacing any earlier one with the same key in place.
+    pub fn register(&mut self, category: &str, prototype: NodePrototype) -> Arc<NodePrototype> {
+        let prototype = Arc::new(prototype);
+        let entry = RegistryEntry {
+            category: category.to_string(),
+            prototype: prototype.clone(),
+        };
+        match self.by_key.get(&prototype.key) {
+            Some(&i) => self.entries[i] = entry,
+            None => {
+                self.by_key
+                    .insert(prototype.key.clone(), self.entries.len());
+                self.entries.push(entry);
+            }
+        }
+        prototype
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Arc<NodePrototype>> {
+        self.by_key.get(key).map(|&i| &self.entries[i].prototype)
+    }
+
+    // Display names are only unique in practice; used to read graphs saved before keys existed.
+    pub fn find_by_name(&self, name: &str) -> Option<&Arc<NodePrototype>> {
+        self.entries
+            .iter()
+            .map(|e| &e.prototype)
+            .find(|p| p.name == name)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &RegistryEntry> {
+        self.entries.iter()
+    }
+
+    // Distinct categories in the order they were first registered. Prototypes registered
+    // without one, e.g. the one of plain groups, aren't offered in menus.
+    pub fn categories(&self) -> Vec<&str> {
+        let mut categories: Vec<&str> = Vec::new();
+        for e in &self.entries {
+            if !e.category.is_empty() && !categories.contains(&e.category.as_str()) {
+                categories.push(&e.category);
+            }
+        }
+        categories
+    }
+}
@@ -0,0 +1,643 @@
+use std::{
+    collections::{HashMap, HashSet, hash_map::DefaultHasher},
+    fmt,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::app::{
+    editor_graph::{self, EvalContext, EvalError, EvalErrorKind, NodeWorld},
+    interpreter::{self, Program, Register},
+    signature::Signatures,
+    storage::ID,
+    value::{Value, ValueType},
+};
+
+// Largest width or height an output can be rendered at.
+pub const MAX_RESOLUTION: usize = 4096;
+
+// Context attributes every pixel is evaluated with. `x` and `y` are normalized to 0..1,
+// `px` and `py` are the pixel coordinates and `width` and `height` the image size.
+pub const PIXEL_ATTRIBUTES: [&str; 6] = ["x", "y", "px", "py", "width", "height"];
+// The pixel attributes that differ between pixels of the same image. Whatever doesn't read them
+// is computed once per render.
+pub const VARYING_ATTRIBUTES: [&str; 4] = ["x", "y", "px", "py"];
+
+// Rows are handed out to render threads in bands of this many, small enough to balance uneven
+// rows across threads without contending on the queue for every row.
+const ROWS_PER_BAND: usize = 16;
+
+// Bytes of intermediate images a `PortCache` keeps for one image size. Ports that don't fit
+// are recomputed every time.
+const CACHE_BUDGET: usize = 256 << 20;
+// Image sizes a `PortCache` keeps images for, enough for a coarse and a full pass.
+const CACHE_FRAMES: usize = 2;
+
+// Render threads share the compiled program, and later the graph itself, by reference.
+const _: () = {
+    const fn assert_sync<T: Send + Sync>() {}
+    assert_sync::<Program>();
+    assert_sync::<NodeWorld>();
+};
+
+pub fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+// Storage for one channel of a rendered pixel, 0..1 mapped to the full range of the type.
+pub trait Channel: Copy + Default + Send + Sync {
+    const BITS: u32;
+
+    // Out of range values saturate.
+    fn from_f32(v: f32) -> Self;
+    fn to_f32(self) -> f32;
+    fn push_be_bytes(self, out: &mut Vec<u8>);
+}
+
+impl Channel for u8 {
+    const BITS: u32 = 8;
+
+    fn from_f32(v: f32) -> Self {
+        (v * 255f32) as u8
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32 / 255f32
+    }
+
+    fn push_be_bytes(self, out: &mut Vec<u8>) {
+        out.push(self);
+    }
+}
+
+impl Channel for u16 {
+    const BITS: u32 = 16;
+
+    fn from_f32(v: f32) -> Self {
+        (v * 65535f32) as u16
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32 / 65535f32
+    }
+
+    fn push_be_bytes(self, out: &mut Vec<u8>) {
+        out.extend(self.to_be_bytes());
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub resolution: Resolution,
+    // Attributes with the same value for every pixel. The pixel attributes themselves can't be
+    // overridden this way.
+    pub globals: HashMap<String, Value>,
+    pub threads: usize,
+}
+
+impl RenderSettings {
+    pub fn new(resolution: Resolution) -> Self {
+        RenderSettings {
+            resolution,
+            globals: HashMap::new(),
+            threads: available_threads(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    // The output isn't connected to anything that compiles, e.g. because of mismatched types.
+    Compile,
+    MissingAttributes(Vec<String>),
+    // `source` is why the tree-walker fails at the same pixel.
+    Evaluation {
+        px: usize,
+        py: usize,
+        source: EvalError,
+    },
+    Cancelled,
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Compile => write!(f, "graph could not be compiled"),
+            RenderError::MissingAttributes(names) => {
+                write!(
+                    f,
+                    "graph reads attributes that aren't set: {}",
+                    names.join(", ")
+                )
+            }
+            RenderError::Evaluation { px, py, .. } => {
+                write!(f, "evaluation failed at pixel ({px}, {py})")
+            }
+            RenderError::Cancelled => write!(f, "render was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RenderError::Evaluation { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Resolution {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Resolution {
+    // Clamps both sides to 1..=MAX_RESOLUTION.
+    pub fn new(width: usize, height: usize) -> Self {
+        Resolution {
+            width: width.clamp(1, MAX_RESOLUTION),
+            height: height.clamp(1, MAX_RESOLUTION),
+        }
+    }
+
+    pub fn size(self) -> [usize; 2] {
+        [self.width, self.height]
+    }
+
+    // Size of an image taking every `step`th pixel in both directions.
+    pub fn sampled(self, step: usize) -> Self {
+        Resolution {
+            width: self.width.div_ceil(step),
+            height: self.height.div_ceil(step),
+        }
+    }
+}
+
+// The attributes of a pixel as the tree-walker takes them, see `PIXEL_ATTRIBUTES`.
+pub fn pixel_context(resolution: Resolution, px: usize, py: usize) -> EvalContext {
+    let Resolution { width, height } = resolution;
+    [
+        ("x", px as f32 / width as f32),
+        ("y", py as f32 / height as f32),
+        ("px", px as f32),
+        ("py", py as f32),
+        ("width", width as f32),
+        ("height", height as f32),
+    ]
+    .into_iter()
+    .map(|(name, val)| (name.to_string(), Value::Float(val)))
+    .collect()
+}
+
+// RGBA with unmultiplied alpha in row-major order, as `ColorImage::from_rgba_unmultiplied`
+// expects. A float output shows up as gray, the same conversion the Out node's colour input
+// applies. Every pixel is evaluated on its own, so the result doesn't depend on `threads`.
+pub fn render_rgba<T: Channel>(
+    world: &NodeWorld,
+    output: ID,
+    settings: &RenderSettings,
+) -> Result<Vec<T>, RenderError> {
+    render_rgba_sampled(world, output, settings, 1, &|| false)
+}
+
+// Port images of one image size, by port signature.
+type Frame = HashMap<u64, Arc<[Value]>>;
+
+// The value of every pixel of some output ports, from an earlier render of the same size.
+// Rendering through a cache reads ports whose upstream hasn't changed since from there, so
+// editing a node only recomputes what is downstream of it.
+#[derive(Default)]
+pub struct PortCache {
+    // Keyed by `frame_key` and then port signature, most recently rendered size last.
+    frames: Vec<(u64, Frame)>,
+}
+
+impl PortCache {
+    fn frame(&self, key: u64) -> Option<&Frame> {
+        self.frames.iter().find(|(k, _)| *k == key).map(|(_, f)| f)
+    }
+
+    // Replaces everything kept for the size, so ports that are gone don't pile up.
+    fn store(&mut self, key: u64, images: Frame) {
+        self.frames.retain(|(k, _)| *k != key);
+        self.frames.push((key, images));
+        if self.frames.len() > CACHE_FRAMES {
+            self.frames.remove(0);
+        }
+    }
+}
+
+// Everything besides the graph that a port's value at some pixel depends on.
+fn frame_key(settings: &RenderSettings, step: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    settings.resolution.size().hash(&mut hasher);
+    step.hash(&mut hasher);
+    let mut globals: Vec<_> = settings.globals.iter().collect();
+    globals.sort_by_key(|(name, _)| *name);
+    for (name, val) in globals {
+        name.hash(&mut hasher);
+        format!("{val:?}").hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// Name of the attribute a cached port is read from. Can't collide with one typed into a node.
+fn cached_attribute(signature: u64) -> String {
+    format!("\0cached {signature:016x}")
+}
+
+// `render_rgba_sampled` reusing and updating the images in `cache`.
+pub fn render_rgba_cached<T: Channel>(
+    world: &NodeWorld,
+    output: ID,
+    settings: &RenderSettings,
+    step: usize,
+    cancelled: &(dyn Fn() -> bool + Sync),
+    cache: &mut PortCache,
+) -> Result<Vec<T>, RenderError> {
+    render(world, output, settings, step, cancelled, Some(cache))
+}
+
+// Like `render_rgba`, but only evaluates every `step`th pixel for an image of
+// `resolution.sampled(step)`, with the attributes those pixels have at full size. Gives up as
+// soon as `cancelled` returns true.
+pub fn render_rgba_sampled<T: Channel>(
+    world: &NodeWorld,
+    output: ID,
+    settings: &RenderSettings,
+    step: usize,
+    cancelled: &(dyn Fn() -> bool + Sync),
+) -> Result<Vec<T>, RenderError> {
+    render(world, output, settings, step, cancelled, None)
+}
+
+fn render<T: Channel>(
+    world: &NodeWorld,
+    output: ID,
+    settings: &RenderSettings,
+    step: usize,
+    cancelled: &(dyn Fn() -> bool + Sync),
+    mut cache: Option<&mut PortCache>,
+) -> Result<Vec<T>, RenderError> {
+    let Resolution { width, height } = settings.resolution.sampled(step);
+    let mut pixels = vec![T::default(); width * height * 4];
+
+    let frame = frame_key(settings, step);
+    let mut signatures = Signatures::new(world);
+    let mut port_signatures = HashMap::new();
+    if cache.is_some() {
+        for id in world.ports.ids() {
+            if world.ports.get(*id).connection_kind.is_output() {
+                port_signatures.insert(*id, signatures.port(*id));
+            }
+        }
+    }
+    let cached = cache.as_deref().and_then(|c| c.frame(frame));
+    let precomputed: HashMap<ID, String> = port_signatures
+        .iter()
+        .filter(|(_, sig)| cached.is_some_and(|c| c.contains_key(*sig)))
+        .map(|(id, sig)| (*id, cached_attribute(*sig)))
+        .collect();
+
+    let (mut program, ports) = interpreter::compile_with_precomputed(world, output, precomputed)
+        .ok_or(RenderError::Compile)?;
+
+    // Images read by the program, by attribute slot, and those kept for the next render: all
+    // of the ports that are still there, also the ones upstream of a cached port.
+    let mut images = Vec::new();
+    let mut kept = HashMap::new();
+    let signatures: HashSet<u64> = port_signatures.values().copied().collect();
+    for sig in &signatures {
+        let Some(image) = cached.and_then(|c| c.get(sig)) else {
+            continue;
+        };
+        if let Some(slot) = program.attribute_slot(&cached_attribute(*sig)) {
+            images.push((slot, image.clone()));
+        }
+        kept.insert(*sig, image.clone());
+    }
+    // The rest of the ports the program computes, downstream ones first since they save the
+    // most work, as long as they fit.
+    let mut captures: Vec<(u64, Register)> = ports
+        .iter()
+        .filter_map(|(id, reg)| Some((*port_signatures.get(id)?, *reg)))
+        .filter(|(sig, _)| !kept.contains_key(sig))
+        .collect();
+    captures.sort_by_key(|(_, reg)| std::cmp::Reverse(reg.index()));
+    let mut seen = HashSet::new();
+    captures.retain(|(sig, _)| seen.insert(*sig));
+    let image_size = width * height * size_of::<Value>();
+    captures.truncate((CACHE_BUDGET / image_size).saturating_sub(kept.len()));
+
+    // The tree-walker would fail on these for every pixel.
+    let missing: Vec<String> = program
+        .attributes()
+        .iter()
+        .enumerate()
+        .filter(|(slot, a)| {
+            !PIXEL_ATTRIBUTES.contains(&a.as_str())
+                && !settings.globals.contains_key(*a)
+                && !images.iter().any(|(s, _)| s == slot)
+        })
+        .map(|(_, a)| a.clone())
+        .collect();
+    if !missing.is_empty() {
+        return Err(RenderError::MissingAttributes(missing));
+    }
+
+    let mut varying: Vec<usize> = VARYING_ATTRIBUTES
+        .iter()
+        .filter_map(|name| program.attribute_slot(name))
+        .collect();
+    varying.extend(images.iter().map(|(slot, _)| *slot));
+    program.hoist(&varying);
+    let mut template = RowRenderer::new(&program, settings, step, &images);
+    // A failure here would happen at every pixel.
+    template
+        .prepare()
+        .ok_or_else(|| evaluation_error(world, output, settings, 0, 0))?;
+
+    let band_len = width * 4 * ROWS_PER_BAND;
+    let bands = Mutex::new(pixels.chunks_mut(band_len).enumerate());
+    let threads = settings.threads.clamp(1, height.div_ceil(ROWS_PER_BAND));
+    let registers: Vec<Register> = captures.iter().map(|(_, reg)| *reg).collect();
+    // Captured values of each band, one list per port.
+    let captured = Mutex::new(Vec::new());
+
+    thread::scope(|s| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut rows = template.clone();
+                    loop {
+                        if cancelled() {
+                            return Err(RenderError::Cancelled);
+                        }
+                        let Some((band, band_pixels)) = bands.lock().unwrap().next() else {
+                            return Ok(());
+                        };
+                        let mut values = vec![Vec::new(); registers.len()];
+                        for (i, row) in band_pixels.chunks_exact_mut(width * 4).enumerate() {
+                            rows.render(band * ROWS_PER_BAND + i, row, &registers, &mut values)
+                                .map_err(|(px, py)| {
+                                    evaluation_error(world, output, settings, px, py)
+                                })?;
+                        }
+                        if !registers.is_empty() {
+                            captured.lock().unwrap().push((band, values));
+                        }
+                    }
+                })
+            })
+            .collect();
+        // Scoped threads are joined when the scope ends, even those skipped after an error.
+        workers.into_iter().try_for_each(|w| w.join().unwrap())
+    })?;
+
+    if let Some(cache) = cache.as_mut() {
+        let mut bands = captured.into_inner().unwrap();
+        bands.sort_by_key(|(band, _)| *band);
+        for (i, (sig, _)) in captures.iter().enumerate() {
+            let image: Vec<Value> = bands
+                .iter()
+                .flat_map(|(_, values)| values[i].iter().copied())
+                .collect();
+            kept.insert(*sig, image.into());
+        }
+        cache.store(frame, kept);
+    }
+
+    Ok(pixels)
+}
+
+// Programs fail where the tree-walker does, so it is asked what went wrong at the pixel. Like
+// rendering, that includes converting the output to a colour. Should the two disagree, the
+// error isn't attributed to any node, rather than taking down the render thread.
+fn evaluation_error(
+    world: &NodeWorld,
+    output: ID,
+    settings: &RenderSettings,
+    px: usize,
+    py: usize,
+) -> RenderError {
+    let mut ctx = pixel_context(settings.resolution, px, py);
+    for (name, val) in &settings.globals {
+        ctx.insert_mut(name.clone(), *val);
+    }
+    let node = world.ports.get(output).node;
+    let source = world
+        .evaluate_output_port(output, ctx)
+        .and_then(|val| {
+            editor_graph::convert(val, ValueType::Color).map_err(|e| e.at(node, output))
+        })
+        .err()
+        .unwrap_or_else(|| EvalError::new(EvalErrorKind::CompiledOnly));
+    RenderError::Evaluation { px, py, source }
+}
+
+// Per-thread registers and attribute values for evaluating `program` one row at a time.
+#[derive(Clone)]
+struct RowRenderer<'a> {
+    program: &'a Program,
+    resolution: Resolution,
+    step: usize,
+    registers: Vec<Value>,
+    attributes: Vec<Value>,
+    // Slots of x, y, px and py.
+    slots: [Option<usize>; 4],
+    // Attribute slots read from one value per sample, see `PortCache`.
+    images: &'a [(usize, Arc<[Value]>)],
+}
+
+impl<'a> RowRenderer<'a> {
+    fn new(
+        program: &'a Program,
+        settings: &RenderSettings,
+        step: usize,
+        images: &'a [(usize, Arc<[Value]>)],
+    ) -> Self {
+        let resolution = settings.resolution;
+        let mut attributes = vec![Value::Float(0f32); program.attributes().len()];
+        for (slot, name) in program.attributes().iter().enumerate() {
+            if let Some(val) = settings.globals.get(name) {
+                attributes[slot] = *val;
+            }
+        }
+        if let Some(slot) = program.attribute_slot("width") {
+            attributes[slot] = Value::Float(resolution.width as f32);
+        }
+        if let Some(slot) = program.attribute_slot("height") {
+            attributes[slot] = Value::Float(resolution.height as f32);
+        }
+
+        RowRenderer {
+            program,
+            resolution,
+            step,
+            registers: program.new_registers(),
+            attributes,
+            slots: VARYING_ATTRIBUTES.map(|name| program.attribute_slot(name)),
+            images,
+        }
+    }
+
+    // Computes what is the same for every pixel, see `Program::hoist`.
+    fn prepare(&mut self) -> Option<()> {
+        self.program
+            .run_uniform(&mut self.registers, &self.attributes)
+    }
+
+    fn set(&mut self, attribute: usize, val: f32) {
+        if let Some(slot) = self.slots[attribute] {
+            self.attributes[slot] = Value::Float(val);
+        }
+    }
+
+    // `y` and the pixels of `row` count samples, every `step`th pixel of the full image. The
+    // values of `captures` at each of them are appended to the list of the same index. Fails
+    // with the full size coordinates of the first pixel that doesn't evaluate.
+    fn render<T: Channel>(
+        &mut self,
+        y: usize,
+        row: &mut [T],
+        captures: &[Register],
+        values: &mut [Vec<Value>],
+    ) -> Result<(), (usize, usize)> {
+        let Resolution { width, height } = self.resolution;
+        let first_sample = y * row.len() / 4;
+        let py = y * self.step;
+        self.set(1, py as f32 / height as f32);
+        self.set(3, py as f32);
+
+        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+            let px = x * self.step;
+            self.set(0, px as f32 / width as f32);
+            self.set(2, px as f32);
+            for (slot, image) in self.images {
+                self.attributes[*slot] = image[first_sample + x];
+            }
+            let color = self
+                .program
+                .run_varying(&mut self.registers, &self.attributes)
+                .and_then(Value::as_color)
+                .ok_or((px, py))?;
+            for (reg, values) in captures.iter().zip(values.iter_mut()) {
+                values.push(self.registers[reg.index()]);
+            }
+            for (out, channel) in pixel.iter_mut().zip(color) {
+                *out = T::from_f32(channel);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{
+        basic_nodes::{
+            basic_registry,
+            expression::EXPRESSION,
+            node_tools::{get_state_f32_mut, get_state_string_mut},
+            noise::FBM,
+            sync_node,
+            with::WITH,
+        },
+        dependencies::Dependencies,
+        group,
+    };
+
+    fn set_string(world: &mut NodeWorld, node: ID, name: &str, val: &str) {
+        *get_state_string_mut(name, &mut world.nodes.get_mut(node).state.state).unwrap() =
+            val.to_string();
+        sync_node(world, node);
+    }
+
+    fn port(world: &NodeWorld, node: ID, index: usize) -> ID {
+        world.nodes.get(node).ports[index]
+    }
+
+    // A single Expression node varying along both axes, and its output port.
+    fn gradient() -> (NodeWorld, ID) {
+        let mut world = NodeWorld::new(Arc::new(basic_registry()));
+        let node = world.create_node(Default::default(), EXPRESSION);
+        set_string(&mut world, node, "expr", "sin(x * 20) * y + px / width");
+        let output = *world.nodes.get(node).ports.last().unwrap();
+        (world, output)
+    }
+
+    #[test]
+    fn output_does_not_depend_on_thread_count() {
+        let (world, output) = gradient();
+        // Tall enough for every thread to get bands, and not a multiple of the band height.
+        let mut settings = RenderSettings::new(Resolution::new(37, ROWS_PER_BAND * 9 + 5));
+        let images: Vec<Vec<u16>> = [1, 2, 7]
+            .into_iter()
+            .map(|threads| {
+                settings.threads = threads;
+                render_rgba(&world, output, &settings).unwrap()
+            })
+            .collect();
+        assert!(images[0].iter().any(|c| *c != images[0][0]));
+        assert_eq!(images[0], images[1]);
+        assert_eq!(images[0], images[2]);
+    }
+
+    // Fractal noise sampled at a warped `x` through a With node, all inside a group, scaled by
+    // `y` outside of it. Returns the port the image is rendered from.
+    fn warped_noise() -> (NodeWorld, ID) {
+        let mut world = NodeWorld::new(Arc::new(basic_registry()));
+        let noise = world.create_node(Default::default(), FBM);
+        *get_state_f32_mut("octaves", &mut world.nodes.get_mut(noise).state.state).unwrap() = 3f32;
+        let warp = world.create_node(Default::default(), EXPRESSION);
+        set_string(&mut world, warp, "expr", "x * 2 + sin(y * 6) / 4");
+        let with = world.create_node(Default::default(), WITH);
+        set_string(&mut world, with, "attributes", "x");
+        world
+            .connect(port(&world, noise, 2), port(&world, with, 0))
+            .unwrap();
+        world
+            .connect(port(&world, warp, 2), port(&world, with, 1))
+            .unwrap();
+
+        // `y` isn't connected, so it reads the attribute.
+        let scale = world.create_node(Default::default(), EXPRESSION);
+        set_string(&mut world, scale, "expr", "v * y");
+        world
+            .connect(port(&world, with, 2), port(&world, scale, 0))
+            .unwrap();
+        let group = group::group_nodes(&mut world, &[noise, warp, with]).unwrap();
+        assert!(world.subgraph(world.nodes.get(group)).is_some());
+        let output = port(&world, scale, 2);
+        (world, output)
+    }
+
+    #[test]
+    fn compiled_render_matches_tree_walker() {
+        let (world, output) = warped_noise();
+        let resolution = Resolution::new(24, 19);
+        let image: Vec<u16> =
+            render_rgba(&world, output, &RenderSettings::new(resolution)).unwrap();
+
+        // Memoized on what each port reads, which the group and With node change.
+        let deps = Arc::new(Dependencies::analyze(&world));
+        let mut expected = Vec::new();
+        for py in 0..resolution.height {
+            for px in 0..resolution.width {
+                let color = world
+                    .evaluate_output_port_with(output, pixel_context(resolution, px, py), &deps)
+                    .unwrap()
+                    .as_color()
+                    .unwrap();
+                expected.extend(color.map(u16::from_f32));
+            }
+        }
+        assert!(image.iter().any(|c| *c != image[0]));
+        assert_eq!(image, expected);
+    }
+}
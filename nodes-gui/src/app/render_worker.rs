@@ -0,0 +1,150 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::app::{
+    editor_graph::NodeWorld,
+    render::{self, PortCache, RenderError, RenderSettings, Resolution},
+    storage::ID,
+};
+
+// The coarse pass evaluates every this many pixels in both directions.
+const COARSE_STEP: usize = 8;
+// Renders expected to finish within a frame skip the coarse pass, it would only flicker.
+const COARSE_MIN_TIME: Duration = Duration::from_millis(16);
+
+struct Job {
+    generation: u64,
+    world: NodeWorld,
+    output: ID,
+    resolution: Resolution,
+}
+
+pub struct RenderedImage {
+    pub size: [usize; 2],
+    pub rgba: Vec<u8>,
+}
+
+pub struct RenderFailure {
+    // Full size, which any pixel coordinates in `error` refer to.
+    pub resolution: Resolution,
+    pub error: RenderError,
+}
+
+type RenderResult = Result<RenderedImage, RenderFailure>;
+
+// Renders on a background thread so editing stays responsive. Every request supersedes the
+// earlier ones: jobs still queued are skipped, the running one is cancelled and results that
+// arrive late are dropped. Intermediate images are kept between jobs, so a job only recomputes
+// what changed since the last one.
+pub struct RenderWorker {
+    jobs: Sender<Job>,
+    results: Receiver<(u64, RenderResult)>,
+    latest: Arc<AtomicU64>,
+}
+
+impl RenderWorker {
+    // `ctx` is asked to repaint whenever a result is ready.
+    pub fn new(ctx: egui::Context) -> Self {
+        let (jobs, job_receiver) = mpsc::channel();
+        let (result_sender, results) = mpsc::channel();
+        let latest = Arc::new(AtomicU64::new(0));
+
+        let worker_latest = latest.clone();
+        // Exits once `jobs` is dropped with the worker.
+        thread::Builder::new()
+            .name("render".to_string())
+            .spawn(move || run(job_receiver, result_sender, worker_latest, ctx))
+            .expect("failed to spawn render thread");
+
+        RenderWorker {
+            jobs,
+            results,
+            latest,
+        }
+    }
+
+    // Takes a snapshot of the graph, so edits made while rendering don't affect the result.
+    pub fn request(&self, world: NodeWorld, output: ID, resolution: Resolution) {
+        let generation = self.latest.fetch_add(1, Ordering::SeqCst) + 1;
+        let _ = self.jobs.send(Job {
+            generation,
+            world,
+            output,
+            resolution,
+        });
+    }
+
+    // The most refined image of the latest request that arrived since the last poll, or why it
+    // couldn't be rendered.
+    pub fn poll(&self) -> Option<RenderResult> {
+        let latest = self.latest.load(Ordering::SeqCst);
+        self.results
+            .try_iter()
+            .filter(|(generation, _)| *generation == latest)
+            .map(|(_, image)| image)
+            .last()
+    }
+}
+
+fn run(
+    jobs: Receiver<Job>,
+    results: Sender<(u64, RenderResult)>,
+    latest: Arc<AtomicU64>,
+    ctx: egui::Context,
+) {
+    let mut cache = PortCache::default();
+    // Of the last full resolution pass, to tell how long the next one will take. Graphs and
+    // cache hits change from job to job, so it is only an estimate.
+    let mut time_per_pixel: Option<Duration> = None;
+    while let Ok(mut job) = jobs.recv() {
+        while let Ok(newer) = jobs.try_recv() {
+            job = newer;
+        }
+        let cancelled = || latest.load(Ordering::SeqCst) != job.generation;
+
+        let Resolution { width, height } = job.resolution;
+        let pixels = (width * height) as u32;
+        let steps: &[usize] = if time_per_pixel.is_none_or(|t| t * pixels > COARSE_MIN_TIME) {
+            &[COARSE_STEP, 1]
+        } else {
+            &[1]
+        };
+        let settings = RenderSettings::new(job.resolution);
+
+        for &step in steps {
+            let start = Instant::now();
+            let result = match render::render_rgba_cached(
+                &job.world, job.output, &settings, step, &cancelled, &mut cache,
+            ) {
+                Ok(rgba) => Ok(RenderedImage {
+                    size: job.resolution.sampled(step).size(),
+                    rgba,
+                }),
+                Err(RenderError::Cancelled) => break,
+                Err(error) => Err(RenderFailure {
+                    resolution: job.resolution,
+                    error,
+                }),
+            };
+            if step == 1 && result.is_ok() {
+                time_per_pixel = Some(start.elapsed() / pixels);
+            }
+            let failed = result.is_err();
+            if results.send((job.generation, result)).is_err() {
+                return;
+            }
+            ctx.request_repaint();
+            // The full pass would fail too.
+            if failed {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::{collections::BTreeMap, collections::HashMap, fmt, path::Path, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::app::{
+    basic_nodes,
+    editor_graph::{NodeWorld, PortKind, StateValue, ValidationError},
+    group,
+    registry::PrototypeRegistry,
+    storage::ID,
+};
+
+// Bump whenever the layout of `SavedWorld` changes in a way old files can't be read with.
+// Version 1 named prototypes by display name rather than registry key.
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct SavedWorld {
+    version: u32,
+    nodes: Vec<SavedNode>,
+}
+
+// Only the version, so unsupported files can be reported before parsing the rest.
+#[derive(Deserialize)]
+struct SavedHeader {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedNode {
+    // The registry key.
+    prototype: String,
+    pos: (f32, f32),
+    state: BTreeMap<String, StateValue>,
+    // Connected inputs only, keyed by input port name.
+    inputs: BTreeMap<String, SavedLink>,
+    // The graph inside a group node. Its ports are derived from this, so `prototype` is only
+    // its key and needn't be registered when loading.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subgraph: Option<Vec<SavedNode>>,
+}
+
+// Points at an output port by its node's index in `SavedWorld::nodes` and the port's name.
+#[derive(Serialize, Deserialize)]
+struct SavedLink {
+    node: usize,
+    port: String,
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(std::io::Error),
+    Serialize(ron::Error),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "could not write file: {e}"),
+            SaveError::Serialize(e) => write!(f, "could not serialize graph: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+    UnsupportedVersion(u32),
+    UnknownPrototypes(Vec<String>),
+    UnknownPort { prototype: String, port: String },
+    UnknownNode(usize),
+    // Each loop as the `SavedWorld::nodes` indices and prototype names along it.
+    Cycles(Vec<Vec<(usize, String)>>),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "could not read file: {e}"),
+            LoadError::Parse(e) => write!(f, "could not parse graph: {e}"),
+            LoadError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported format version {v} (expected at most {FORMAT_VERSION})"
+            ),
+            LoadError::UnknownPrototypes(names) => {
+                write!(f, "unknown node prototypes: {}", names.join(", "))
+            }
+            LoadError::UnknownPort { prototype, port } => {
+                write!(f, "node \"{prototype}\" has no port named \"{port}\"")
+            }
+            LoadError::UnknownNode(i) => write!(f, "link refers to missing node {i}"),
+            LoadError::Cycles(cycles) => {
+                write!(f, "graph contains cycles:")?;
+                for cycle in cycles {
+                    let names: Vec<String> = cycle
+                        .iter()
+                        .map(|(i, name)| format!("{name} #{i}"))
+                        .collect();
+                    write!(f, " [{}]", names.join(" -> "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+pub fn save_world(world: &NodeWorld) -> Result<String, SaveError> {
+    let saved = SavedWorld {
+        version: FORMAT_VERSION,
+        nodes: save_nodes(world),
+    };
+    ron::ser::to_string_pretty(&saved, ron::ser::PrettyConfig::default())
+        .map_err(SaveError::Serialize)
+}
+
+fn save_nodes(world: &NodeWorld) -> Vec<SavedNode> {
+    let indices: HashMap<ID, usize> = world
+        .nodes
+        .ids()
+        .enumerate()
+        .map(|(i, id)| (*id, i))
+        .collect();
+
+    let mut nodes = Vec::new();
+    for n in world.nodes.iter() {
+        let mut inputs = BTreeMap::new();
+        for p in &n.ports {
+            if let PortKind::Input(Some(out)) = world.ports.get(*p).connection_kind
+                && let Some(out_port) = world.ports.try_get(out)
+            {
+                inputs.insert(
+                    world.port_info(*p).name.clone(),
+                    SavedLink {
+                        node: indices[&out_port.node],
+                        port: world.port_info(out).name.clone(),
+                    },
+                );
+            }
+        }
+
+        nodes.push(SavedNode {
+            prototype: n.key.clone(),
+            pos: (n.pos.x, n.pos.y),
+            state: n.state.state.clone().into_iter().collect(),
+            inputs,
+            subgraph: world.subgraph(n).map(|inner| save_nodes(inner)),
+        });
+    }
+    nodes
+}
+
+// Rebinds every saved node to the current definition registered under its key, so changes to a
+// prototype apply to graphs saved before them.
+pub fn load_world(text: &str, registry: &Arc<PrototypeRegistry>) -> Result<NodeWorld, LoadError> {
+    let header: SavedHeader = ron::from_str(text).map_err(LoadError::Parse)?;
+    if header.version == 0 || header.version > FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(header.version));
+    }
+    let saved: SavedWorld = ron::from_str(text).map_err(LoadError::Parse)?;
+    load_nodes(&saved.nodes, registry, header.version)
+}
+
+// Errors inside a group's subgraph refer to indices within that subgraph.
+fn load_nodes(
+    saved: &[SavedNode],
+    registry: &Arc<PrototypeRegistry>,
+    version: u32,
+) -> Result<NodeWorld, LoadError> {
+    let find_prototype = |name: &str| match version {
+        1 => registry.find_by_name(name),
+        _ => registry.get(name),
+    };
+    let group_key = |name: &str| match version {
+        1 if name == "Group" => group::GROUP.to_string(),
+        1 => group::asset_key(name),
+        _ => name.to_string(),
+    };
+
+    let mut unknown: Vec<String> = saved
+        .iter()
+        .filter(|n| n.subgraph.is_none() && find_prototype(&n.prototype).is_none())
+        .map(|n| n.prototype.clone())
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        unknown.dedup();
+        return Err(LoadError::UnknownPrototypes(unknown));
+    }
+
+    let mut world = NodeWorld::new(registry.clone());
+    let mut ids = Vec::new();
+    for n in saved {
+        let pos = egui::pos2(n.pos.0, n.pos.1);
+        let id = match &n.subgraph {
+            // Groups are loaded with the graph they were saved with. Instances of assets that
+            // aren't there anymore become plain groups.
+            Some(inner) => {
+                let key = group_key(&n.prototype);
+                let id = match registry.get(&key) {
+                    Some(p) if p.subgraph.is_some() => world.create_node(pos, &key),
+                    _ => world.create_node(pos, group::GROUP),
+                };
+                group::apply_edit(&mut world, id, load_nodes(inner, registry, version)?);
+                id
+            }
+            None => world.create_node(pos, &find_prototype(&n.prototype).unwrap().key.clone()),
+        };
+
+        // Values whose kind no longer matches the prototype are dropped so render functions
+        // can keep assuming their state has the shape they declared.
+        let state = &mut world.nodes.get_mut(id).state.state;
+        for (key, val) in &n.state {
+            if let Some(current) = state.get_mut(key)
+                && std::mem::discriminant(current) == std::mem::discriminant(val)
+            {
+                *current = val.clone();
+            }
+        }
+        basic_nodes::sync_node(&mut world, id);
+
+        ids.push(id);
+    }
+
+    for (n, id) in saved.iter().zip(&ids) {
+        for (inp_name, link) in &n.inputs {
+            let inp =
+                find_port(&world, *id, inp_name, true).ok_or_else(|| LoadError::UnknownPort {
+                    prototype: n.prototype.clone(),
+                    port: inp_name.clone(),
+                })?;
+
+            let out_node = *ids
+                .get(link.node)
+                .ok_or(LoadError::UnknownNode(link.node))?;
+            let out = find_port(&world, out_node, &link.port, false).ok_or_else(|| {
+                LoadError::UnknownPort {
+                    prototype: saved[link.node].prototype.clone(),
+                    port: link.port.clone(),
+                }
+            })?;
+
+            world.ports.get_mut(inp).connection_kind = PortKind::Input(Some(out));
+        }
+    }
+
+    // Evaluating a loop never terminates, so such files are refused rather than opened.
+    let cycles: Vec<Vec<(usize, String)>> = world
+        .validate()
+        .into_iter()
+        .filter_map(|e| match e {
+            ValidationError::Cycle(nodes) => Some(nodes),
+            // Every link was just resolved against ports of this world.
+            ValidationError::DanglingLink { .. } => None,
+        })
+        .map(|nodes| {
+            nodes
+                .iter()
+                .map(|id| {
+                    let i = ids.iter().position(|n| n == id).unwrap();
+                    (i, saved[i].prototype.clone())
+                })
+                .collect()
+        })
+        .collect();
+    if !cycles.is_empty() {
+        return Err(LoadError::Cycles(cycles));
+    }
+
+    Ok(world)
+}
+
+fn find_port(world: &NodeWorld, node: ID, name: &str, input: bool) -> Option<ID> {
+    world.nodes.get(node).ports.iter().copied().find(|p| {
+        world.port_info(*p).name == name && world.ports.get(*p).connection_kind.is_input() == input
+    })
+}
+
+pub fn save_world_to_file(world: &NodeWorld, path: &Path) -> Result<(), SaveError> {
+    let text = save_world(world)?;
+    std::fs::write(path, text).map_err(SaveError::Io)
+}
+
+pub fn load_world_from_file(
+    path: &Path,
+    registry: &Arc<PrototypeRegistry>,
+) -> Result<NodeWorld, LoadError> {
+    let text = std::fs::read_to_string(path).map_err(LoadError::Io)?;
+    load_world(&text, registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{
+        basic_nodes::{
+            basic_registry, expression::EXPRESSION, image::OUT, node_tools::get_state_string_mut,
+            noise::FBM,
+        },
+        render::{RenderSettings, Resolution, render_rgba},
+    };
+
+    fn port(world: &NodeWorld, node: ID, index: usize) -> ID {
+        world.nodes.get(node).ports[index]
+    }
+
+    fn expression(world: &mut NodeWorld, formula: &str) -> ID {
+        let node = world.create_node(egui::pos2(10f32, 20f32), EXPRESSION);
+        *get_state_string_mut("expr", &mut world.nodes.get_mut(node).state.state).unwrap() =
+            formula.to_string();
+        basic_nodes::sync_node(world, node);
+        node
+    }
+
+    // Noise warped by an expression inside a group, scaled outside of it and drawn to Out.
+    fn world() -> NodeWorld {
+        let mut world = NodeWorld::new(Arc::new(basic_registry()));
+        let warp = expression(&mut world, "x * 2 + y");
+        let noise = world.create_node(egui::pos2(-40f32, 5f32), FBM);
+        world
+            .connect(port(&world, warp, 2), port(&world, noise, 0))
+            .unwrap();
+        let scale = expression(&mut world, "v * 0.5 + 0.5");
+        world
+            .connect(port(&world, noise, 2), port(&world, scale, 0))
+            .unwrap();
+        let out = world.create_node(egui::pos2(300f32, 0f32), OUT);
+        world
+            .connect(port(&world, scale, 1), port(&world, out, 0))
+            .unwrap();
+        group::group_nodes(&mut world, &[warp, noise]).unwrap();
+        world
+    }
+
+    fn image(world: &NodeWorld) -> Vec<u16> {
+        let out = *world
+            .nodes
+            .with_ids()
+            .into_iter()
+            .find(|(_, n)| n.key == OUT)
+            .unwrap()
+            .0;
+        let PortKind::Input(Some(output)) = world.ports.get(port(world, out, 0)).connection_kind
+        else {
+            panic!("Out node is not connected");
+        };
+        render_rgba(world, output, &RenderSettings::new(Resolution::new(12, 7))).unwrap()
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let world = world();
+        let text = save_world(&world).unwrap();
+        let loaded = load_world(&text, world.registry()).unwrap();
+
+        assert_eq!(save_world(&loaded).unwrap(), text);
+        assert_eq!(loaded.nodes.ids().count(), world.nodes.ids().count());
+        let before = image(&world);
+        assert!(before.iter().any(|c| *c != before[0]));
+        assert_eq!(image(&loaded), before);
+    }
+
+    #[test]
+    fn unknown_prototypes_are_reported() {
+        let registry = Arc::new(basic_registry());
+        let text = save_world(&world()).unwrap();
+
+        let renamed = text.replacen("\"out\"", "\"missing\"", 1);
+        assert_ne!(renamed, text);
+        match load_world(&renamed, &registry) {
+            Err(LoadError::UnknownPrototypes(names)) => assert_eq!(names, ["missing"]),
+            other => panic!("expected unknown prototypes, got {:?}", other.err()),
+        }
+
+        // Also inside a group's subgraph.
+        let renamed = text.replacen("\"fbm\"", "\"missing\"", 1);
+        assert_ne!(renamed, text);
+        assert!(matches!(
+            load_world(&renamed, &registry),
+            Err(LoadError::UnknownPrototypes(_))
+        ));
+    }
+}
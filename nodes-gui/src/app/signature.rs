@@ -0,0 +1,85 @@
+use std::{
+    collections::{HashMap, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+};
+
+use crate::app::{
+    editor_graph::{NodeWorld, PortKind, StateValue},
+    storage::ID,
+};
+
+// Fingerprints of what a port computes: its node's kind, state and ports, and everything
+// upstream of it. A port's signature changes exactly when it or something feeding it is edited,
+// so comparing signatures tells which parts of a graph are dirty. Positions aren't included.
+pub struct Signatures<'a> {
+    world: &'a NodeWorld,
+    nodes: HashMap<ID, u64>,
+}
+
+impl<'a> Signatures<'a> {
+    pub fn new(world: &'a NodeWorld) -> Self {
+        Signatures {
+            world,
+            nodes: HashMap::new(),
+        }
+    }
+
+    pub fn port(&mut self, id: ID) -> u64 {
+        let port = self.world.ports.get(id);
+        let mut hasher = DefaultHasher::new();
+        self.node(port.node).hash(&mut hasher);
+        self.world.port_info(id).name.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn node(&mut self, id: ID) -> u64 {
+        if let Some(sig) = self.nodes.get(&id) {
+            return *sig;
+        }
+        let world = self.world;
+        let node = world.nodes.get(id);
+
+        let mut hasher = DefaultHasher::new();
+        node.key.hash(&mut hasher);
+        let mut state: Vec<_> = node.state.state.iter().collect();
+        state.sort_by_key(|(name, _)| *name);
+        for (name, val) in state {
+            name.hash(&mut hasher);
+            hash_state(val, &mut hasher);
+        }
+        for p in &node.ports {
+            let info = world.port_info(*p);
+            info.name.hash(&mut hasher);
+            info.ty.hash(&mut hasher);
+            if let PortKind::Input(source) = world.ports.get(*p).connection_kind {
+                source.map(|s| self.port(s)).hash(&mut hasher);
+            }
+        }
+        if let Some(inner) = world.subgraph(node) {
+            world_signature(inner).hash(&mut hasher);
+        }
+
+        let sig = hasher.finish();
+        self.nodes.insert(id, sig);
+        sig
+    }
+}
+
+// Covers every node of `world`, including ones nothing is connected to.
+pub fn world_signature(world: &NodeWorld) -> u64 {
+    let mut signatures = Signatures::new(world);
+    let mut hasher = DefaultHasher::new();
+    for id in world.nodes.ids() {
+        signatures.node(*id).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn hash_state(val: &StateValue, hasher: &mut impl Hasher) {
+    match val {
+        StateValue::Float(f) => (0u8, f.to_bits()).hash(hasher),
+        StateValue::Char(c) => (1u8, c).hash(hasher),
+        StateValue::String(s) => (2u8, s).hash(hasher),
+        StateValue::Color(c) => (3u8, c.map(f32::to_bits)).hash(hasher),
+    }
+}
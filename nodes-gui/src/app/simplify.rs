@@ -0,0 +1,324 @@
+use std::{collections::HashSet, fmt};
+
+use crate::app::{
+    basic_nodes::{
+        self,
+        add::BINARY_MATH,
+        constant::CONSTANT,
+        group_io::GROUP_INPUT,
+        math::MATH,
+        node_tools::{get_state_char, get_state_f32, get_state_f32_mut, get_state_string},
+    },
+    dependencies::Dependencies,
+    editor_graph::{EvalContext, NodeWorld, PortKind},
+    storage::ID,
+    value::{Value, ValueType},
+};
+
+// What `simplify` changed, by node name.
+#[derive(Default, Debug)]
+pub struct Report {
+    // Nodes with the same value at every pixel, replaced by a Constant holding it.
+    pub folded: Vec<String>,
+    // Operations passing one of their inputs through unchanged, e.g. adding 0.
+    pub bypassed: Vec<String>,
+    // Nodes no output depends on.
+    pub removed: Vec<String>,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.folded.is_empty() && self.bypassed.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "nothing to simplify");
+        }
+        let parts: Vec<String> = [
+            ("folded", &self.folded),
+            ("bypassed", &self.bypassed),
+            ("removed", &self.removed),
+        ]
+        .iter()
+        .filter(|(_, names)| !names.is_empty())
+        .map(|(what, names)| format!("{what} {} ({})", names.len(), names.join(", ")))
+        .collect();
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+// Rewrites `world` to compute the same outputs with fewer nodes: constant subgraphs become
+// Constant nodes, identity operations are skipped and nodes nothing is drawn from are removed.
+pub fn simplify(world: &mut NodeWorld) -> Report {
+    let mut report = Report::default();
+    fold_constants(world, &mut report);
+    bypass_identities(world, &mut report);
+    remove_unused(world, &mut report);
+    report
+}
+
+fn node_name(world: &NodeWorld, node: ID) -> String {
+    world.prototype(world.nodes.get(node)).name.clone()
+}
+
+fn output_port(world: &NodeWorld, node: ID) -> Option<ID> {
+    let mut outputs = world
+        .nodes
+        .get(node)
+        .ports
+        .iter()
+        .filter(|p| world.ports.get(**p).connection_kind.is_output());
+    match (outputs.next(), outputs.next()) {
+        (Some(p), None) => Some(*p),
+        _ => None,
+    }
+}
+
+// Inputs reading from `port`.
+fn consumers(world: &NodeWorld, port: ID) -> Vec<ID> {
+    world
+        .ports
+        .with_ids()
+        .into_iter()
+        .filter(|(_, p)| matches!(p.connection_kind, PortKind::Input(Some(out)) if out == port))
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+// Links everything reading from `from` to `to` instead.
+fn reroute(world: &mut NodeWorld, from: ID, to: ID) {
+    for inp in consumers(world, from) {
+        world.ports.get_mut(inp).connection_kind = PortKind::Input(Some(to));
+    }
+}
+
+// Only float nodes with a single output are folded, those are what a Constant can stand in
+// for. Of a constant chain only the last node is replaced, the rest is left unused.
+fn fold_constants(world: &mut NodeWorld, report: &mut Report) {
+    let deps = Dependencies::analyze(world);
+    let mut folds = Vec::new();
+    for node in world.nodes.ids() {
+        if world.nodes.get(*node).key == CONSTANT {
+            continue;
+        }
+        let Some(port) = output_port(world, *node) else {
+            continue;
+        };
+        if world.port_info(port).ty != ValueType::Float
+            || !deps.get(port).is_some_and(|d| d.is_empty())
+        {
+            continue;
+        }
+        if let Ok(Value::Float(val)) = world.evaluate_output_port(port, EvalContext::new()) {
+            folds.push((*node, port, val));
+        }
+    }
+
+    let folded: HashSet<ID> = folds.iter().map(|(node, _, _)| *node).collect();
+    for (node, port, val) in folds {
+        let used_elsewhere = consumers(world, port)
+            .iter()
+            .any(|inp| !folded.contains(&world.ports.get(*inp).node));
+        if !used_elsewhere {
+            continue;
+        }
+        let replacement = world.create_node(world.nodes.get(node).pos, CONSTANT);
+        *get_state_f32_mut("val", &mut world.nodes.get_mut(replacement).state.state).unwrap() = val;
+        reroute(world, port, world.nodes.get(replacement).ports[0]);
+        report.folded.push(node_name(world, node));
+        world.remove_node(node);
+    }
+}
+
+// The value of a Constant node feeding `input`.
+fn constant_input(world: &NodeWorld, input: Option<ID>) -> Option<f32> {
+    let node = world.nodes.get(world.ports.try_get(input?)?.node);
+    if node.key != CONSTANT {
+        return None;
+    }
+    get_state_f32("val", &node.state.state)
+}
+
+// The input an arithmetic node passes through unchanged, if any.
+fn identity_input(world: &NodeWorld, node: ID) -> Option<&'static str> {
+    let n = world.nodes.get(node);
+    let state = &n.state.state;
+    let op = match n.key.as_str() {
+        BINARY_MATH => match get_state_char("op", state)? {
+            '+' => "add",
+            '-' => "sub",
+            '*' => "mul",
+            '/' => "div",
+            _ => return None,
+        },
+        MATH => get_state_string("op", state)?.as_str(),
+        _ => return None,
+    };
+    let inputs = world.direct_inputs(node);
+    let a = constant_input(world, *inputs.get("A")?);
+    let b = constant_input(world, *inputs.get("B")?);
+    match (op, a, b) {
+        ("add", Some(0f32), _) | ("mul", Some(1f32), _) => Some("B"),
+        ("add" | "sub", _, Some(0f32)) | ("mul" | "div", _, Some(1f32)) => Some("A"),
+        _ => None,
+    }
+}
+
+// Only when the kept input is linked to a float, otherwise the node converts or reads an
+// attribute and isn't a plain pass-through.
+fn bypass_identities(world: &mut NodeWorld, report: &mut Report) {
+    for node in world.nodes.ids().copied().collect::<Vec<_>>() {
+        let Some(kept) = identity_input(world, node) else {
+            continue;
+        };
+        let Some(Some(source)) = world.direct_inputs(node).get(kept).copied() else {
+            continue;
+        };
+        let Some(port) = output_port(world, node) else {
+            continue;
+        };
+        if world.port_info(source).ty != ValueType::Float {
+            continue;
+        }
+        reroute(world, port, source);
+        report.bypassed.push(node_name(world, node));
+        world.remove_node(node);
+    }
+}
+
+// A graph without outputs is left alone, it is likely still being built.
+fn remove_unused(world: &mut NodeWorld, report: &mut Report) {
+    let outputs = basic_nodes::output_nodes(world);
+    if outputs.is_empty() {
+        return;
+    }
+
+    // Group inputs make up the group's ports, whether anything reads them or not.
+    let mut used = basic_nodes::used_nodes(world, &outputs);
+    used.extend(
+        world
+            .nodes
+            .with_ids()
+            .into_iter()
+            .filter(|(_, n)| n.key == GROUP_INPUT)
+            .map(|(id, _)| *id),
+    );
+
+    for node in world.nodes.ids().copied().collect::<Vec<_>>() {
+        if !used.contains(&node) {
+            report.removed.push(node_name(world, node));
+            world.remove_node(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::app::{
+        basic_nodes::{
+            basic_registry, expression::EXPRESSION, image::OUT, node_tools::get_state_string_mut,
+            sync_node,
+        },
+        render::{RenderSettings, Resolution, render_rgba},
+    };
+
+    fn port(world: &NodeWorld, node: ID, index: usize) -> ID {
+        world.nodes.get(node).ports[index]
+    }
+
+    fn constant(world: &mut NodeWorld, val: f32) -> ID {
+        let node = world.create_node(Default::default(), CONSTANT);
+        *get_state_f32_mut("val", &mut world.nodes.get_mut(node).state.state).unwrap() = val;
+        port(world, node, 0)
+    }
+
+    fn expression(world: &mut NodeWorld, formula: &str) -> ID {
+        let node = world.create_node(Default::default(), EXPRESSION);
+        *get_state_string_mut("expr", &mut world.nodes.get_mut(node).state.state).unwrap() =
+            formula.to_string();
+        sync_node(world, node);
+        *world.nodes.get(node).ports.last().unwrap()
+    }
+
+    // Adds `a` and `b`, returning the output port.
+    fn add(world: &mut NodeWorld, a: ID, b: ID) -> ID {
+        let node = world.create_node(Default::default(), BINARY_MATH);
+        world.connect(a, port(world, node, 0)).unwrap();
+        world.connect(b, port(world, node, 1)).unwrap();
+        port(world, node, 2)
+    }
+
+    // `(0 + gradient) + (0.25 + 0.125)`, drawn to an Out node next to an Expression nothing
+    // reads. Stays below 1, so no pixel clips.
+    fn world() -> (NodeWorld, ID) {
+        let mut world = NodeWorld::new(Arc::new(basic_registry()));
+        let gradient = expression(&mut world, "x * 0.3 + y * 0.2");
+        let zero = constant(&mut world, 0f32);
+        let passed = add(&mut world, zero, gradient);
+        let (quarter, eighth) = (
+            constant(&mut world, 0.25f32),
+            constant(&mut world, 0.125f32),
+        );
+        let constant_sum = add(&mut world, quarter, eighth);
+        let result = add(&mut world, passed, constant_sum);
+        let out = world.create_node(Default::default(), OUT);
+        world.connect(result, port(&world, out, 0)).unwrap();
+        expression(&mut world, "x");
+        (world, out)
+    }
+
+    fn render(world: &NodeWorld, out: ID) -> Vec<u16> {
+        let PortKind::Input(Some(output)) = world.ports.get(port(world, out, 0)).connection_kind
+        else {
+            panic!("Out node is not connected");
+        };
+        render_rgba(world, output, &RenderSettings::new(Resolution::new(16, 9))).unwrap()
+    }
+
+    fn sorted(names: &[String]) -> Vec<&str> {
+        let mut names: Vec<&str> = names.iter().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn renders_the_same_after_simplifying() {
+        let (mut world, out) = world();
+        let before = render(&world, out);
+        assert!(before.iter().any(|c| *c != before[0]));
+
+        simplify(&mut world);
+        assert_eq!(render(&world, out), before);
+        assert_eq!(world.nodes.ids().count(), 4);
+    }
+
+    #[test]
+    fn report_lists_every_change() {
+        let (mut world, _) = world();
+        let report = simplify(&mut world);
+
+        assert_eq!(report.folded, ["Binary Math"]);
+        assert_eq!(report.bypassed, ["Binary Math"]);
+        assert_eq!(
+            sorted(&report.removed),
+            ["Constant", "Constant", "Constant", "Expression"]
+        );
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "folded 1 (Binary Math); bypassed 1 (Binary Math); removed 4 ({})",
+                report.removed.join(", ")
+            )
+        );
+
+        // Nothing is left to do the second time.
+        let again = simplify(&mut world);
+        assert!(again.is_empty(), "{again}");
+    }
+}
@@ -1,125 +1,177 @@
-use std::collections::HashMap;
+use rpds::{HashTrieMapSync, StackSync, VectorSync};
 
+// Slots are reused after removal, the generation tells a reused slot apart from the handle
+// that pointed at its previous occupant.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub struct ID(usize);
+pub struct ID {
+    index: usize,
+    generation: u32,
+}
 
+// Backed by persistent collections, so a clone shares every entry with the original until
+// one side changes it. Snapshots for undo cost about the size of the edit, not of the graph.
 #[derive(Clone)]
 pub struct Storage<T> {
-    entries: Vec<T>,
-    ids: Vec<ID>,
-    ids_to_inds: HashMap<ID, usize>,
+    entries: VectorSync<T>,
+    ids: VectorSync<ID>,
+    ids_to_inds: HashTrieMapSync<ID, usize>,
     next_unallocated_id: ID,
-    unused_ids: Vec<ID>,
+    unused_ids: StackSync<ID>,
 }
 
 impl<T> Default for Storage<T> {
     fn default() -> Self {
         Self {
-            entries: Default::default(),
-            ids: Default::default(),
-            ids_to_inds: Default::default(),
-            next_unallocated_id: ID(0),
-            unused_ids: Default::default(),
+            entries: VectorSync::new_sync(),
+            ids: VectorSync::new_sync(),
+            ids_to_inds: HashTrieMapSync::new_sync(),
+            next_unallocated_id: ID {
+                index: 0,
+                generation: 0,
+            },
+            unused_ids: StackSync::new_sync(),
         }
     }
 }
 
-impl<T> Storage<T> {
+impl<T: Clone> Storage<T> {
     fn get_next_id(&mut self) -> ID {
-        if let Some(id) = self.unused_ids.pop() {
-            return id;
+        if let Some(id) = self.unused_ids.peek().copied() {
+            self.unused_ids.pop_mut();
+            return ID {
+                index: id.index,
+                generation: id.generation.wrapping_add(1),
+            };
         }
 
         let new_id = self.next_unallocated_id;
-        self.next_unallocated_id = ID(self.next_unallocated_id.0 + 1);
-        return new_id;
+        self.next_unallocated_id = ID {
+            index: new_id.index + 1,
+            generation: 0,
+        };
+        new_id
     }
 
     pub fn create(&mut self, obj: T) -> (&mut T, ID) {
         let id = self.get_next_id();
-        self.entries.push(obj);
-        self.ids.push(id);
+        self.entries.push_back_mut(obj);
+        self.ids.push_back_mut(id);
         let ind = self.entries.len() - 1;
-        self.ids_to_inds.insert(id, ind);
-        (&mut self.entries[ind], id)
+        self.ids_to_inds.insert_mut(id, ind);
+        (self.entries.get_mut(ind).unwrap(), id)
     }
 
-    pub fn remove(&mut self, id: ID) {
-        let ind = self.ids_to_inds.remove(&id).unwrap();
-        self.unused_ids.push(id);
-
-        if ind == self.entries.len() - 1 {
-            self.entries.pop();
-            self.ids.pop();
-            return;
+    // Returns the removed entry, or `None` if `id` is unknown or stale.
+    pub fn remove(&mut self, id: ID) -> Option<T> {
+        let ind = *self.ids_to_inds.get(&id)?;
+        self.ids_to_inds.remove_mut(&id);
+        self.unused_ids.push_mut(id);
+
+        // Swap-remove: the last entry fills the gap.
+        let removed = self.entries[ind].clone();
+        let last = self.entries.len() - 1;
+        if ind < last {
+            let moved = self.ids[last];
+            self.entries.set_mut(ind, self.entries[last].clone());
+            self.ids.set_mut(ind, moved);
+            self.ids_to_inds.insert_mut(moved, ind);
         }
+        self.entries.drop_last_mut();
+        self.ids.drop_last_mut();
 
-        let node_to_move = self.entries.pop().unwrap();
-        let id_to_update = self.ids.pop().unwrap();
-        self.ids_to_inds.insert(id_to_update, ind);
-        self.entries[ind] = node_to_move;
-        self.ids[ind] = id_to_update;
+        Some(removed)
     }
 
+    // Panics if `id` is unknown or stale, see `try_get` for a fallible lookup.
     pub fn get(&self, id: ID) -> &T {
-        &self.entries[*self.ids_to_inds.get(&id).unwrap()]
+        self.try_get(id).expect("unknown or stale storage ID")
     }
 
     pub fn get_mut(&mut self, id: ID) -> &mut T {
-        &mut self.entries[*self.ids_to_inds.get(&id).unwrap()]
+        self.try_get_mut(id).expect("unknown or stale storage ID")
     }
 
-    pub fn exists(&self, id: ID) -> bool {
-        self.ids_to_inds.contains_key(&id)
+    pub fn try_get(&self, id: ID) -> Option<&T> {
+        self.entries.get(*self.ids_to_inds.get(&id)?)
     }
 
-    pub fn iter(&self) -> std::slice::Iter<'_, T> {
-        self.entries.iter()
+    // Copies the entry first if a clone of this storage still shares it.
+    pub fn try_get_mut(&mut self, id: ID) -> Option<&mut T> {
+        self.entries.get_mut(*self.ids_to_inds.get(&id)?)
     }
 
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
-        self.entries.iter_mut()
+    pub fn exists(&self, id: ID) -> bool {
+        self.ids_to_inds.contains_key(&id)
     }
 
-    pub fn ids(&self) -> &Vec<ID> {
-        &self.ids
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter()
     }
 
-    pub fn with_ids(&self) -> impl IntoIterator<Item = (&ID, &T)> {
-        self.ids.iter().zip(&self.entries)
+    pub fn ids(&self) -> impl Iterator<Item = &ID> {
+        self.ids.iter()
     }
 
-    pub fn with_ids_mut(&mut self) -> impl IntoIterator<Item = (&ID, &mut T)> {
-        self.ids.iter().zip(&mut self.entries)
+    pub fn with_ids(&self) -> impl IntoIterator<Item = (&ID, &T)> {
+        self.ids.iter().zip(self.entries.iter())
     }
 }
 
-impl<T> IntoIterator for Storage<T> {
-    type Item = T;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    type IntoIter = std::vec::IntoIter<T>;
+    #[test]
+    fn reused_slot_gets_new_generation() {
+        let mut storage = Storage::default();
+        let (_, first) = storage.create(1);
+        assert_eq!(storage.remove(first), Some(1));
+        let (_, second) = storage.create(2);
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.entries.into_iter()
+        assert_eq!(second.index, first.index);
+        assert_eq!(second.generation, first.generation + 1);
+        assert_ne!(first, second);
     }
-}
-
-impl<'a, T> IntoIterator for &'a Storage<T> {
-    type Item = &'a T;
 
-    type IntoIter = std::slice::Iter<'a, T>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        self.iter()
+    #[test]
+    fn stale_id_is_not_found() {
+        let mut storage = Storage::default();
+        let (_, stale) = storage.create(1);
+        storage.remove(stale);
+        let (_, fresh) = storage.create(2);
+
+        assert!(!storage.exists(stale));
+        assert_eq!(storage.try_get(stale), None);
+        assert_eq!(storage.try_get_mut(stale), None);
+        assert_eq!(storage.try_get(fresh), Some(&2));
     }
-}
 
-impl<'a, T> IntoIterator for &'a mut Storage<T> {
-    type Item = &'a mut T;
+    #[test]
+    fn removing_stale_id_keeps_new_occupant() {
+        let mut storage = Storage::default();
+        let (_, stale) = storage.create(1);
+        storage.remove(stale);
+        let (_, fresh) = storage.create(2);
 
-    type IntoIter = std::slice::IterMut<'a, T>;
+        assert_eq!(storage.remove(stale), None);
+        assert_eq!(storage.try_get(fresh), Some(&2));
+        assert_eq!(storage.ids().collect::<Vec<_>>(), vec![&fresh]);
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.iter_mut()
+    #[test]
+    fn clone_keeps_its_entries_after_edits() {
+        let mut storage = Storage::default();
+        let (_, kept) = storage.create(1);
+        let (_, removed) = storage.create(2);
+        let snapshot = storage.clone();
+
+        *storage.get_mut(kept) = 3;
+        storage.remove(removed);
+        storage.create(4);
+
+        assert_eq!(snapshot.try_get(kept), Some(&1));
+        assert_eq!(snapshot.try_get(removed), Some(&2));
+        assert_eq!(snapshot.iter().count(), 2);
+        assert_eq!(storage.try_get(kept), Some(&3));
     }
 }
@@ -0,0 +1,128 @@
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ValueType {
+    Float,
+    Vec2,
+    Vec3,
+    Color,
+    Bool,
+    Int,
+}
+
+impl ValueType {
+    pub const ALL: [ValueType; 6] = [
+        ValueType::Float,
+        ValueType::Vec2,
+        ValueType::Vec3,
+        ValueType::Color,
+        ValueType::Bool,
+        ValueType::Int,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|ty| ty.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::Float => "Float",
+            ValueType::Vec2 => "Vec2",
+            ValueType::Vec3 => "Vec3",
+            ValueType::Color => "Color",
+            ValueType::Bool => "Bool",
+            ValueType::Int => "Int",
+        }
+    }
+
+    fn is_scalar(self) -> bool {
+        matches!(self, ValueType::Float | ValueType::Bool | ValueType::Int)
+    }
+
+    // Whether `Value::convert` can turn values of this type into `to`. Scalars broadcast to
+    // every vector type and vectors widen, but nothing narrows implicitly except colour to Vec3.
+    pub fn converts_to(self, to: ValueType) -> bool {
+        if self == to || (self.is_scalar() && to.is_scalar()) {
+            return true;
+        }
+        match (self, to) {
+            (from, ValueType::Vec2 | ValueType::Vec3 | ValueType::Color) if from.is_scalar() => {
+                true
+            }
+            (ValueType::Vec2, ValueType::Vec3 | ValueType::Color) => true,
+            (ValueType::Vec3, ValueType::Color) | (ValueType::Color, ValueType::Vec3) => true,
+            _ => false,
+        }
+    }
+}
+
+// Colours are linear RGBA with unmultiplied alpha.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Value {
+    Float(f32),
+    Vec2([f32; 2]),
+    Vec3([f32; 3]),
+    Color([f32; 4]),
+    Bool(bool),
+    Int(i32),
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Float(_) => ValueType::Float,
+            Value::Vec2(_) => ValueType::Vec2,
+            Value::Vec3(_) => ValueType::Vec3,
+            Value::Color(_) => ValueType::Color,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Int(_) => ValueType::Int,
+        }
+    }
+
+    fn scalar(&self) -> Option<f32> {
+        match *self {
+            Value::Float(f) => Some(f),
+            Value::Bool(b) => Some(if b { 1f32 } else { 0f32 }),
+            Value::Int(i) => Some(i as f32),
+            _ => None,
+        }
+    }
+
+    // Implicit conversion between port types, `None` where `ValueType::converts_to` is false.
+    pub fn convert(self, to: ValueType) -> Option<Value> {
+        if self.value_type() == to {
+            return Some(self);
+        }
+
+        if let Some(f) = self.scalar() {
+            return Some(match to {
+                ValueType::Float => Value::Float(f),
+                ValueType::Vec2 => Value::Vec2([f; 2]),
+                ValueType::Vec3 => Value::Vec3([f; 3]),
+                ValueType::Color => Value::Color([f, f, f, 1f32]),
+                ValueType::Bool => Value::Bool(f != 0f32),
+                ValueType::Int => Value::Int(f as i32),
+            });
+        }
+
+        match (self, to) {
+            (Value::Vec2([x, y]), ValueType::Vec3) => Some(Value::Vec3([x, y, 0f32])),
+            (Value::Vec2([x, y]), ValueType::Color) => Some(Value::Color([x, y, 0f32, 1f32])),
+            (Value::Vec3([x, y, z]), ValueType::Color) => Some(Value::Color([x, y, z, 1f32])),
+            (Value::Color([r, g, b, _]), ValueType::Vec3) => Some(Value::Vec3([r, g, b])),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(self) -> Option<f32> {
+        match self.convert(ValueType::Float)? {
+            Value::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn as_color(self) -> Option<[f32; 4]> {
+        match self.convert(ValueType::Color)? {
+            Value::Color(c) => Some(c),
+            _ => None,
+        }
+    }
+}
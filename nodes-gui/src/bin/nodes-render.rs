@@ -0,0 +1,195 @@
+// Renders the Out node of a saved graph to an image file without opening a window, e.g.
+//
+//     nodes-render graph.ron out.png --width 512 --set time=0.5
+
+use std::{collections::HashMap, path::PathBuf, process::ExitCode, sync::Arc};
+
+use nodes_gui::app::{
+    basic_nodes::{
+        basic_registry,
+        image::{self, output_resolution},
+    },
+    editor_graph::PortKind,
+    export::{self, BitDepth, ColorMode, ExportError},
+    render::{PIXEL_ATTRIBUTES, RenderSettings, Resolution},
+    serialization, simplify,
+    value::Value,
+};
+
+const USAGE: &str = "usage: nodes-render <graph.ron> <image.png|image.pgm> [--width N] \
+[--height N] [--depth 8|16] [--gray] [--threads N] [--simplify] [--set name=value]...
+
+Values given to --set are `true`, `false` or one to four comma-separated numbers.
+--simplify folds constants and drops unused nodes before rendering and reports what changed.";
+
+struct Args {
+    graph: PathBuf,
+    image: PathBuf,
+    // Default to the size set on the Out node.
+    width: Option<usize>,
+    height: Option<usize>,
+    depth: BitDepth,
+    // PGM files are gray either way.
+    mode: ColorMode,
+    threads: Option<usize>,
+    simplify: bool,
+    globals: HashMap<String, Value>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut paths = Vec::new();
+    let mut width = None;
+    let mut height = None;
+    let mut depth = BitDepth::Eight;
+    let mut mode = ColorMode::Rgba;
+    let mut threads = None;
+    let mut simplify = false;
+    let mut globals = HashMap::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or(format!("{flag} needs a value"));
+        match arg.as_str() {
+            "--width" => width = Some(parse_size("--width", &value("--width")?)?),
+            "--height" => height = Some(parse_size("--height", &value("--height")?)?),
+            "--depth" => {
+                depth = match value("--depth")?.as_str() {
+                    "8" => BitDepth::Eight,
+                    "16" => BitDepth::Sixteen,
+                    other => return Err(format!("--depth expects 8 or 16, got \"{other}\"")),
+                }
+            }
+            "--gray" => mode = ColorMode::Gray,
+            "--simplify" => simplify = true,
+            "--threads" => threads = Some(parse_size("--threads", &value("--threads")?)?),
+            "--set" => {
+                let assignment = value("--set")?;
+                let (name, val) = assignment
+                    .split_once('=')
+                    .ok_or(format!("expected name=value, got \"{assignment}\""))?;
+                if PIXEL_ATTRIBUTES.contains(&name) {
+                    return Err(format!(
+                        "\"{name}\" is set for every pixel and can't be overridden"
+                    ));
+                }
+                globals.insert(name.to_string(), parse_value(val)?);
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option {flag}")),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    let [graph, image]: [PathBuf; 2] = paths
+        .try_into()
+        .map_err(|_| "expected a graph file and an image file".to_string())?;
+    Ok(Args {
+        graph,
+        image,
+        width,
+        height,
+        depth,
+        mode,
+        threads,
+        simplify,
+        globals,
+    })
+}
+
+fn parse_size(flag: &str, text: &str) -> Result<usize, String> {
+    match text.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{flag} expects a positive integer, got \"{text}\"")),
+    }
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    match text {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+
+    let numbers: Vec<f32> = text
+        .split(',')
+        .map(|n| n.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("can't parse \"{text}\" as a value"))?;
+    match numbers[..] {
+        [f] => Ok(Value::Float(f)),
+        [x, y] => Ok(Value::Vec2([x, y])),
+        [x, y, z] => Ok(Value::Vec3([x, y, z])),
+        [r, g, b, a] => Ok(Value::Color([r, g, b, a])),
+        _ => Err(format!("\"{text}\" has more than four components")),
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    // Checked up front rather than after a possibly long render.
+    if export::ImageFormat::from_path(&args.image).is_none() {
+        return Err(
+            export::ExportError::UnknownFormat(args.image.display().to_string()).to_string(),
+        );
+    }
+
+    let mut world = serialization::load_world_from_file(&args.graph, &Arc::new(basic_registry()))
+        .map_err(|e| format!("{}: {e}", args.graph.display()))?;
+    if args.simplify {
+        eprintln!("nodes-render: {}", simplify::simplify(&mut world));
+    }
+
+    let out = world
+        .nodes
+        .iter()
+        .find(|n| n.key == image::OUT)
+        .ok_or("graph has no Out node")?;
+    let output = match world.ports.get(out.ports[0]).connection_kind {
+        PortKind::Input(Some(output)) => output,
+        _ => return Err("the Out node isn't connected".to_string()),
+    };
+
+    let default = output_resolution(&out.state.state);
+    let mut settings = RenderSettings::new(Resolution::new(
+        args.width.unwrap_or(default.width),
+        args.height.unwrap_or(default.height),
+    ));
+    settings.globals = args.globals;
+    if let Some(threads) = args.threads {
+        settings.threads = threads;
+    }
+
+    export::render_to_file(
+        &world,
+        output,
+        &settings,
+        &args.image,
+        args.depth,
+        args.mode,
+    )
+    .map_err(|e| match e {
+        e @ ExportError::Render(_) => e.to_string(),
+        e => format!("{}: {e}", args.image.display()),
+    })
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+
+    let args = match parse_args(args.into_iter()) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("nodes-render: {e}\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("nodes-render: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,4 +1,4 @@
-mod app;
+pub mod app;
 pub use app::App;
 
 pub mod interpreter;